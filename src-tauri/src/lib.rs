@@ -1,68 +1,292 @@
 use opencv::{
+    calib3d,
     core,
+    face,
     imgcodecs,
     imgproc,
     objdetect,
+    photo,
+    videoio,
+    xphoto,
     prelude::*,
 };
 use base64::{Engine as _, engine::general_purpose};
 use rayon::prelude::*;
+use tauri::Manager;
 
 #[derive(serde::Serialize)]
 struct FaceResult {
     base64: String,       // 切り抜き後の透過画像
     debug_base64: String, // 青枠と赤枠を描画した確認用画像
+    score: f64,           // 検出の信頼度（Haarのlevel weight）
+    verified: Option<bool>, // 目の検証結果（検証OFFならNone）
+    landmarks: Option<Vec<[f32; 2]>>, // 68点ランドマーク（元画像座標、モデルが無い場合はNone）
+    pose: Option<HeadPose>,           // 顔の向き（ランドマークから推定）
+    quality: FaceQuality,             // 画質の指標
+    session_id: String,               // refine_maskでマスクを修正する時のID
+    face_rect: FaceRect,              // 顔矩形（元画像座標）
+    canvas_rect: FaceRect,            // 切り抜いたキャンバスの範囲（元画像座標）
+    mask_base64: Option<String>,      // アルファマスクだけのPNG（include_layersの時）
+    label_map_base64: Option<String>, // GrabCutのラベルを色分けしたPNG（include_layersの時）
+    contour: Option<Vec<[i32; 2]>>,   // マスクの外形の多角形（元画像座標、include_layersの時）
+    timings: MaskTimings,             // マスク生成の処理時間
+    warnings: Vec<String>,            // 省略した処理とその理由（ランドマークのモデルが無いなど）
 }
 
-#[tauri::command]
-fn process_face(path: String) -> Result<Vec<FaceResult>, String> { // 戻り値の型を変更
-    println!("process_face() invoked: Debug Mode");
+// マスク生成の処理時間（max_work_sizeで縮小した時と比べる用）
+#[derive(serde::Serialize, Clone, Copy)]
+struct MaskTimings {
+    grab_cut_ms: f64,  // 前処理 + GrabCut + 整形（作業解像度）
+    upsample_ms: f64,  // 元の解像度へのエッジ保持アップサンプル（縮小しなければ0）
+    work_width: i32,   // GrabCutを実行した解像度
+    work_height: i32,
+    iterations: i32,   // 収束までに実際に反復した回数
+}
+
+// 顔領域の画質指標
+#[derive(serde::Serialize, Clone, Copy)]
+struct FaceQuality {
+    width: i32,                 // 顔矩形の幅（px）
+    height: i32,                // 顔矩形の高さ（px）
+    sharpness: f64,             // ラプラシアンの分散（大きいほどシャープ）
+    highlight_clip_ratio: f64,  // 白飛びしている画素の割合
+    shadow_clip_ratio: f64,     // 黒つぶれしている画素の割合
+    skin_coverage: f64,         // 肌色画素の割合（低いと遮蔽物やマスクの可能性）
+}
+
+// assess_facesの戻り値（1顔分）
+#[derive(serde::Serialize)]
+struct FaceAssessment {
+    rect: FaceRect,
+    score: f64,
+    quality: FaceQuality,
+}
+
+// 元画像座標での矩形（JSON用）
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy)]
+struct FaceRect {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32,
+}
+
+impl From<core::Rect> for FaceRect {
+    fn from(r: core::Rect) -> Self {
+        FaceRect { x: r.x, y: r.y, width: r.width, height: r.height }
+    }
+}
+
+// 顔の向き（度）。yaw: 左右, pitch: 上下, roll: 傾き
+#[derive(serde::Serialize, Clone, Copy)]
+struct HeadPose {
+    yaw: f64,
+    pitch: f64,
+    roll: f64,
+}
+
+// 顔検出の設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct DetectOptions {
+    nms_iou_threshold: f64,      // これ以上重なる矩形は低スコア側を除外
+    containment_threshold: f64,  // 小さい矩形がこの割合以上大きい矩形に含まれていたら除外（入れ子対策）
+    min_score: f64,              // これ未満の信頼度の候補は捨てる
+    verify: VerifyMode,          // 目の検出による誤検出チェック
+    min_eyes: usize,             // 検証に合格するのに必要な目の数
+}
+
+// 誤検出チェックの扱い方
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum VerifyMode {
+    Off,      // 検証しない
+    Drop,     // 目が見つからない候補は除外
+    DownRank, // 除外せずに順位を下げる
+}
+
+impl Default for DetectOptions {
+    fn default() -> Self {
+        DetectOptions {
+            nms_iou_threshold: 0.3,
+            containment_threshold: 0.8,
+            min_score: f64::NEG_INFINITY,
+            verify: VerifyMode::Off,
+            min_eyes: 1,
+        }
+    }
+}
+
+// 検出された顔（矩形 + 信頼度）
+#[derive(Clone, Copy)]
+struct DetectedFace {
+    rect: core::Rect,
+    score: f64,
+    verified: Option<bool>,
+}
+
+// 検出結果（採用された顔と、検証で除外された候補）
+struct FaceDetection {
+    faces: Vec<DetectedFace>,
+    rejected: Vec<DetectedFace>,
+}
+
+// 切り抜きの設定（余白はすべて顔矩形の幅・高さに対する倍率）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct ExtractOptions {
+    preset: Option<ExtractPreset>, // 指定するとプリセットの値を使う（下の余白は無視）
+    matting: Option<MattingOptions>, // 指定すると縁を半透明のアルファにする（Noneなら2値マスク）
+    include_layers: bool,  // マスク・ラベル・外形も返す（自前で合成し直す編集ソフト向け）
+    max_work_size: Option<i32>, // GrabCutをこの長辺（px）まで縮小して実行し、マスクだけ元の解像度に戻す
+    max_iterations: i32,         // GrabCutの反復回数の上限
+    iteration_step: i32,         // 収束判定の間隔（この回数ずつ反復する）
+    convergence_threshold: f64,  // 1ステップで変わった前景画素の割合がこれ未満なら打ち切る
+    canvas_top: f64,     // キャンバス: 顔の上に取る余白（髪）
+    canvas_bottom: f64,  // キャンバス: 顔の下に取る余白（首）
+    canvas_side: f64,    // キャンバス: 左右の余白（横髪）
+    hint_side: f64,      // ヒント枠: キャンバスの左右から除外する幅
+    hint_top: f64,       // ヒント枠: キャンバスの上から除外する幅
+    hint_bottom: f64,    // ヒント枠: キャンバスの下から除外する幅
+}
+
+// よく使う切り抜き範囲
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ExtractPreset {
+    Face,      // 顔だけ（髪はほぼ含めない）
+    FaceHair,  // 顔 + 髪（process_faceの従来の範囲）
+    FullHead,  // 頭全体 + 首
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        ExtractPreset::FaceHair.options()
+    }
+}
+
+impl ExtractPreset {
+    fn options(self) -> ExtractOptions {
+        let (canvas_top, canvas_bottom, canvas_side, hint_side, hint_top, hint_bottom) = match self {
+            ExtractPreset::Face => (0.25, 0.15, 0.1, 0.1, 0.05, 0.1),
+            ExtractPreset::FaceHair => (1.0, 0.2, 0.3, 0.15, 0.05, 0.3),
+            ExtractPreset::FullHead => (1.0, 0.8, 0.4, 0.2, 0.05, 0.05),
+        };
+        ExtractOptions {
+            preset: None,
+            matting: None,
+            include_layers: false,
+            max_work_size: None,
+            max_iterations: 25,
+            iteration_step: 1,
+            convergence_threshold: 0.001,
+            canvas_top,
+            canvas_bottom,
+            canvas_side,
+            hint_side,
+            hint_top,
+            hint_bottom,
+        }
+    }
+}
+
+impl ExtractOptions {
+    // face swap用: 顎を含めるためヒント枠の下の除外を15%に減らす
+    fn for_swap() -> Self {
+        ExtractOptions { hint_bottom: 0.15, ..ExtractOptions::default() }
+    }
+
+    // プリセット指定があれば余白をその値に置き換える
+    fn resolved(&self) -> ExtractOptions {
+        let mut options = self.clone();
+        if let Some(preset) = self.preset {
+            let margins = preset.options();
+            options.canvas_top = margins.canvas_top;
+            options.canvas_bottom = margins.canvas_bottom;
+            options.canvas_side = margins.canvas_side;
+            options.hint_side = margins.hint_side;
+            options.hint_top = margins.hint_top;
+            options.hint_bottom = margins.hint_bottom;
+        }
+        options
+    }
+}
+
+// アルファマッティングの設定（GrabCutの結果からトライマップを作って縁だけ推定し直す）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct MattingOptions {
+    foreground_erode: i32,  // マスクをこの幅（px）だけ削った内側を確実な前景にする
+    background_dilate: i32, // マスクをこの幅（px）だけ広げた外側を確実な背景にする
+    radius: i32,            // ガイデッドフィルタの窓の半径（px）
+    eps: f64,               // ガイデッドフィルタの正則化（大きいほど滑らか）
+    decontaminate: bool,    // 縁の画素から背景色の混ざりを取り除く
+    background_sigma: f64,  // 縁の背景色を推定する時のぼかし（σ）
+}
+
+impl Default for MattingOptions {
+    fn default() -> Self {
+        MattingOptions {
+            foreground_erode: 4,
+            background_dilate: 8,
+            radius: 8,
+            eps: 1e-3,
+            decontaminate: true,
+            background_sigma: 10.0,
+        }
+    }
+}
+
+// 切り抜きの範囲（canvasは元画像座標、hintはキャンバス相対座標）
+struct ExtractGeometry {
+    canvas: core::Rect,
+    hint: core::Rect,
+}
 
+#[tauri::command]
+fn process_face(path: String, detect_options: Option<DetectOptions>, extract_options: Option<ExtractOptions>) -> Result<Vec<FaceResult>, String> { // 戻り値の型を変更
     opencv::core::set_use_optimized(true).ok();
     opencv::core::set_num_threads(0).ok();
 
     let img = imgcodecs::imread(&path, imgcodecs::IMREAD_COLOR)
         .map_err(|_| "画像の読み込みに失敗")?;
 
-    let faces = detect_faces(&img)?;
-    let faces_vec: Vec<core::Rect> = faces.iter().collect();
+    let detection = detect_faces(&img, &detect_options.unwrap_or_default())?;
+    let faces_vec = &detection.faces;
+    let extract_options = extract_options.unwrap_or_default();
 
     if faces_vec.is_empty() {
         return Err("顔が検出されませんでした".to_string());
     }
 
-    // 並列処理
-    let results: Result<Vec<FaceResult>, String> = faces_vec.par_iter().map(|face| {
-        let img_size = img.size().map_err(|e| e.to_string())?;
-
-        // 1. キャンバス確保 (margin設定)
-        let canvas_margin_top = (face.height as f32 * 1.0) as i32;     // 髪全体を含める
-        let canvas_margin_bottom = (face.height as f32 * 0.2) as i32;  // 首は最小限
-        let canvas_margin_side = (face.width as f32 * 0.3) as i32;     // 横髪も含める
+    // ランドマークと顔の向き（モデルが無くても切り抜き自体は続行し、警告で知らせる）
+    let face_rects: Vec<core::Rect> = faces_vec.iter().map(|f| f.rect).collect();
+    let img_size = img.size().map_err(|e| e.to_string())?;
+    let mut warnings = Vec::new();
+    let landmarks_vec: Vec<Option<Vec<core::Point2f>>> = match load_facemark().and_then(|mut facemark| detect_landmarks(&mut facemark, &img, &face_rects)) {
+        Ok(all) => all.into_iter().map(Some).collect(),
+        Err(e) => {
+            warnings.push(format!("ランドマークと顔の向きを省略しました: {}", e));
+            vec![None; faces_vec.len()]
+        }
+    };
 
-        let canvas_x = (face.x - canvas_margin_side).max(0);
-        let canvas_y = (face.y - canvas_margin_top).max(0);
-        let canvas_w = (face.width + canvas_margin_side * 2).min(img_size.width - canvas_x);
-        let canvas_h = (face.height + canvas_margin_top + canvas_margin_bottom).min(img_size.height - canvas_y);
+    // 並列処理（スコア順を保ったまま結果を返す）
+    let results: Result<Vec<FaceResult>, String> = faces_vec.par_iter().zip(landmarks_vec.par_iter()).map(|(detected, landmarks)| {
+        let face = &detected.rect;
 
-        let canvas_rect = core::Rect::new(canvas_x, canvas_y, canvas_w, canvas_h);
+        // 1. キャンバス確保と 2. ヒント枠 (AI探索範囲)
+        let geometry = extract_geometry(face, img_size, &extract_options);
+        let canvas_rect = geometry.canvas;
+        let hint_rect = geometry.hint;
+        let canvas_x = canvas_rect.x;
+        let canvas_y = canvas_rect.y;
 
         // 作業用画像 (Canvas) 切り出し
         let canvas_roi = core::Mat::roi(&img, canvas_rect).map_err(|e| e.to_string())?;
         let mut work_img = core::Mat::default();
         canvas_roi.copy_to(&mut work_img).map_err(|e| e.to_string())?;
 
-        // 2. ヒント枠 (AI探索範囲) - 顔と髪の中心部分に限定
-        let hint_margin_x = (face.width as f32 * 0.15) as i32;   // 左右から15%除外（耳周辺を除外）
-        let hint_margin_top = (face.height as f32 * 0.05) as i32; // 上部から5%除外
-        let hint_margin_bottom = (face.height as f32 * 0.3) as i32; // 下部から30%除外（顎を残しつつ首を除外）
-        
-        let hint_x = hint_margin_x;
-        let hint_y = hint_margin_top;
-        let hint_w = (work_img.cols() - hint_margin_x * 2).max(1);
-        let hint_h = (work_img.rows() - hint_margin_top - hint_margin_bottom).max(1);
-        let hint_rect = core::Rect::new(hint_x, hint_y, hint_w, hint_h);
-
         // --- ★ここが追加: デバッグ画像の作成 ---
         let mut debug_img = work_img.clone();
 
@@ -87,6 +311,26 @@ fn process_face(path: String) -> Result<Vec<FaceResult>, String> { // 戻り値
             2, imgproc::LINE_8, 0
         ).map_err(|e| e.to_string())?;
 
+        // (C) 黄色の枠: 目の検証で除外・降格された候補
+        for other in detection.rejected.iter().chain(faces_vec.iter().filter(|f| f.verified == Some(false))) {
+            let rel_rect = core::Rect::new(other.rect.x - canvas_x, other.rect.y - canvas_y, other.rect.width, other.rect.height);
+            imgproc::rectangle(
+                &mut debug_img,
+                rel_rect,
+                core::Scalar::new(0.0, 255.0, 255.0, 0.0), // BGRなので黄(0,255,255)
+                2, imgproc::LINE_8, 0
+            ).map_err(|e| e.to_string())?;
+        }
+
+        // (D) 緑の点: ランドマーク
+        if let Some(points) = landmarks {
+            for p in points {
+                let rel = core::Point::new(p.x as i32 - canvas_x, p.y as i32 - canvas_y);
+                imgproc::circle(&mut debug_img, rel, 2, core::Scalar::new(0.0, 255.0, 0.0, 0.0), -1, imgproc::LINE_8, 0)
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+
         // デバッグ画像のエンコード (JPEGで軽く済ます)
         let mut debug_buf = core::Vector::<u8>::new();
         imgcodecs::imencode(".jpg", &debug_img, &mut debug_buf, &core::Vector::new())
@@ -96,42 +340,519 @@ fn process_face(path: String) -> Result<Vec<FaceResult>, String> { // 戻り値
         // ------------------------------------
 
         // 3. マスク生成 (GrabCut)
-        let mask = create_high_quality_mask(&work_img, hint_rect)?;
+        let (grab_cut_state, mask, timings) = segment_canvas(&work_img, hint_rect, &extract_options)?;
+
+        // 4. 仕上げ処理（マッティングするなら縁を半透明にして背景色を除く）
+        let (colors, alpha) = finish_cutout(&work_img, &mask, extract_options.matting.as_ref())?;
+        let base64_img = apply_mask_and_encode_parallel(&colors, &alpha)?;
 
-        // 4. 仕上げ処理
-        let base64_img = apply_mask_and_encode_parallel(&work_img, &mask)?;
+        // 編集ソフト向けの素材（マスク・ラベル・外形）
+        let (mask_base64, label_map_base64, contour) = if extract_options.include_layers {
+            (
+                Some(encode_png_base64(&alpha)?),
+                Some(encode_png_base64(&visualize_grab_cut_labels(&grab_cut_state.labels, work_img.size().map_err(|e| e.to_string())?)?)?),
+                Some(mask_contour(&alpha, core::Point::new(canvas_x, canvas_y))?),
+            )
+        } else {
+            (None, None, None)
+        };
+
+        // 5. 後から修正できるようにGrabCutの状態を保存
+        let session_id = store_mask_session(work_img, canvas_rect, grab_cut_state, &extract_options)?;
 
         // 結果をセットで返す
         Ok(FaceResult {
             base64: base64_img,
             debug_base64: debug_base64,
+            score: detected.score,
+            verified: detected.verified,
+            landmarks: landmarks.as_ref().map(|points| points.iter().map(|p| [p.x, p.y]).collect()),
+            pose: match landmarks {
+                Some(points) => Some(estimate_head_pose(points, img_size)?),
+                None => None,
+            },
+            quality: assess_face_quality(&img, *face)?,
+            session_id,
+            face_rect: FaceRect::from(*face),
+            canvas_rect: FaceRect::from(canvas_rect),
+            mask_base64,
+            label_map_base64,
+            contour,
+            timings,
+            warnings: warnings.clone(),
         })
     }).collect();
 
     results
 }
 
-fn detect_faces(img: &core::Mat) -> Result<core::Vector<core::Rect>, String> {
-    let mut face_detector = objdetect::CascadeClassifier::new("haarcascade_frontalface_default.xml")
+// 検出した全ての顔の画質指標だけを返す（切り抜きはしない）
+#[tauri::command]
+fn assess_faces(path: String, detect_options: Option<DetectOptions>) -> Result<Vec<FaceAssessment>, String> {
+    let img = imgcodecs::imread(&path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "画像の読み込みに失敗")?;
+
+    let detection = detect_faces(&img, &detect_options.unwrap_or_default())?;
+    if detection.faces.is_empty() {
+        return Err("顔が検出されませんでした".to_string());
+    }
+
+    detection.faces.iter().map(|face| {
+        Ok(FaceAssessment {
+            rect: face.rect.into(),
+            score: face.score,
+            quality: assess_face_quality(&img, face.rect)?,
+        })
+    }).collect()
+}
+
+fn detect_faces(img: &core::Mat, options: &DetectOptions) -> Result<FaceDetection, String> {
+    let mut face_detector = objdetect::CascadeClassifier::new(&model_path("haarcascade_frontalface_default.xml"))
         .map_err(|_| "xmlファイルが見つかりません")?;
+    // ファイルが無くてもnewは失敗しないので空かどうかで判定する
+    if face_detector.empty().map_err(|e| e.to_string())? {
+        return Err("haarcascade_frontalface_default.xmlが見つかりません".to_string());
+    }
 
-    // ここを修正
+    // detect_multi_scale3で各矩形の信頼度（level weight）も受け取る
     let mut faces = core::Vector::<core::Rect>::new();
-    
-    face_detector.detect_multi_scale(
+    let mut reject_levels = core::Vector::<i32>::new();
+    let mut level_weights = core::Vector::<f64>::new();
+
+    face_detector.detect_multi_scale3(
         img,
         &mut faces,
+        &mut reject_levels,
+        &mut level_weights,
         1.1,
         5,
         0,
         core::Size::new(30, 30),
         core::Size::new(0, 0),
+        true,
+    ).map_err(|e| e.to_string())?;
+
+    let candidates: Vec<DetectedFace> = faces.iter()
+        .zip(level_weights.iter())
+        .map(|(rect, score)| DetectedFace { rect, score, verified: None })
+        .filter(|face| face.score >= options.min_score)
+        .collect();
+
+    let faces = non_max_suppression(candidates, options);
+
+    if options.verify == VerifyMode::Off {
+        return Ok(FaceDetection { faces, rejected: Vec::new() });
+    }
+
+    verify_faces(img, faces, options)
+}
+
+// アプリのリソースフォルダ（起動時にsetupで設定。バンドルしたモデルファイルが入る）
+static RESOURCE_DIR: std::sync::OnceLock<std::path::PathBuf> = std::sync::OnceLock::new();
+
+// モデルファイルのパス: 環境変数FACESWAP_MODEL_DIR → リソースフォルダの順に探し、
+// どちらにも無ければ名前をそのまま返す（開発時はsrc-tauriがカレントディレクトリ）
+fn model_path(name: &str) -> String {
+    let dirs = [
+        std::env::var_os("FACESWAP_MODEL_DIR").map(std::path::PathBuf::from),
+        RESOURCE_DIR.get().cloned(),
+    ];
+    dirs.into_iter()
+        .flatten()
+        .map(|dir| dir.join(name))
+        .find(|path| path.exists())
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|| name.to_string())
+}
+
+// 目の検出で顔候補を検証（柄物の服や壁紙の誤検出対策）
+fn verify_faces(img: &core::Mat, faces: Vec<DetectedFace>, options: &DetectOptions) -> Result<FaceDetection, String> {
+    let mut eye_detector = objdetect::CascadeClassifier::new(&model_path("haarcascade_eye.xml"))
+        .map_err(|_| "haarcascade_eye.xmlが見つかりません")?;
+    if eye_detector.empty().map_err(|e| e.to_string())? {
+        return Err("haarcascade_eye.xmlが見つかりません（READMEの「モデルファイル」を参照）".to_string());
+    }
+
+    let mut gray = core::Mat::default();
+    imgproc::cvt_color(img, &mut gray, imgproc::COLOR_BGR2GRAY, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    let mut accepted = Vec::new();
+    let mut failed = Vec::new();
+    for mut face in faces {
+        let eyes = count_eyes(&mut eye_detector, &gray, &face.rect)?;
+        let ok = eyes >= options.min_eyes;
+        face.verified = Some(ok);
+        if ok {
+            accepted.push(face);
+        } else {
+            failed.push(face);
+        }
+    }
+
+    match options.verify {
+        VerifyMode::DownRank => {
+            // 合格した顔を先に、不合格の顔はその後ろ（それぞれスコア順のまま）
+            accepted.extend(failed);
+            Ok(FaceDetection { faces: accepted, rejected: Vec::new() })
+        }
+        _ => Ok(FaceDetection { faces: accepted, rejected: failed }),
+    }
+}
+
+// 顔矩形の上半分で目を探して個数を返す
+fn count_eyes(eye_detector: &mut objdetect::CascadeClassifier, gray: &core::Mat, face: &core::Rect) -> Result<usize, String> {
+    // 目は顔の上側60%にあるはず（口や鼻の穴の誤検出を避ける）
+    let upper = core::Rect::new(face.x, face.y, face.width, (face.height as f32 * 0.6) as i32);
+    let roi = core::Mat::roi(gray, upper).map_err(|e| e.to_string())?;
+
+    let min_eye = (face.width / 10).max(5);
+    let max_eye = (face.width / 2).max(min_eye + 1);
+
+    let mut eyes = core::Vector::<core::Rect>::new();
+    eye_detector.detect_multi_scale(
+        &roi,
+        &mut eyes,
+        1.1,
+        3,
+        0,
+        core::Size::new(min_eye, min_eye),
+        core::Size::new(max_eye, max_eye),
     ).map_err(|e| e.to_string())?;
 
-    Ok(faces)
+    Ok(eyes.len())
+}
+
+// LBFの68点ランドマークモデルを読み込む
+fn load_facemark() -> Result<core::Ptr<face::Facemark>, String> {
+    let mut facemark = face::create_facemark_lbf().map_err(|e| e.to_string())?;
+    let path = model_path("lbfmodel.yaml");
+    if !std::path::Path::new(&path).exists() {
+        return Err("lbfmodel.yamlが見つかりません（READMEの「モデルファイル」を参照）".to_string());
+    }
+    facemark.load_model(&path)
+        .map_err(|_| "lbfmodel.yamlを読み込めません")?;
+    Ok(facemark)
+}
+
+// 各顔矩形について68点ランドマークを検出（顔と同じ順番で返す）
+fn detect_landmarks(facemark: &mut core::Ptr<face::Facemark>, img: &core::Mat, faces: &[core::Rect]) -> Result<Vec<Vec<core::Point2f>>, String> {
+    let rects: core::Vector<core::Rect> = faces.iter().copied().collect();
+    let mut landmarks = core::Vector::<core::Vector<core::Point2f>>::new();
+
+    let ok = facemark.fit(img, &rects, &mut landmarks).map_err(|e| e.to_string())?;
+    if !ok || landmarks.len() != faces.len() {
+        return Err("ランドマークの検出に失敗".to_string());
+    }
+
+    Ok(landmarks.iter().map(|points| points.to_vec()).collect())
+}
+
+// ランドマークモデルは読み込みが重いので、必要になった時に一度だけ読み込む
+struct LazyFacemark {
+    facemark: Option<Result<core::Ptr<face::Facemark>, String>>,
+}
+
+impl LazyFacemark {
+    fn new() -> Self {
+        LazyFacemark { facemark: None }
+    }
+
+    // 1つの顔矩形のランドマークを返す
+    fn landmarks(&mut self, img: &core::Mat, rect: core::Rect) -> Result<Vec<core::Point2f>, String> {
+        let facemark = self.facemark.get_or_insert_with(load_facemark).as_mut().map_err(|e| e.clone())?;
+        Ok(detect_landmarks(facemark, img, &[rect])?.remove(0))
+    }
+}
+
+// 68点ランドマークの部位ごとの番号範囲
+const LANDMARK_JAW: std::ops::Range<usize> = 0..17;
+const LANDMARK_RIGHT_EYEBROW: std::ops::Range<usize> = 17..22;
+const LANDMARK_LEFT_EYEBROW: std::ops::Range<usize> = 22..27;
+const LANDMARK_NOSE: std::ops::Range<usize> = 27..36;
+const LANDMARK_RIGHT_EYE: std::ops::Range<usize> = 36..42;
+const LANDMARK_LEFT_EYE: std::ops::Range<usize> = 42..48;
+const LANDMARK_OUTER_MOUTH: std::ops::Range<usize> = 48..60;
+const LANDMARK_INNER_MOUTH: std::ops::Range<usize> = 60..68;
+
+// ランドマークの部位ごとに凸包を塗った画像全体サイズのマスク（膨張+ぼかしで縁を柔らかく）
+fn landmark_region_mask(size: core::Size, landmarks: &[core::Point2f], regions: &[std::ops::Range<usize>], dilate: i32, feather: f64) -> Result<core::Mat, String> {
+    let mut mask = core::Mat::new_size_with_default(size, core::CV_8UC1, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+
+    for region in regions {
+        let points = core::Vector::<core::Point>::from_iter(
+            landmarks[region.clone()].iter().map(|p| core::Point::new(p.x.round() as i32, p.y.round() as i32))
+        );
+        let mut hull = core::Vector::<core::Point>::new();
+        imgproc::convex_hull(&points, &mut hull, false, true).map_err(|e| e.to_string())?;
+        imgproc::fill_convex_poly(&mut mask, &hull, core::Scalar::all(255.0), imgproc::LINE_8, 0).map_err(|e| e.to_string())?;
+    }
+
+    if dilate > 0 {
+        let kernel = imgproc::get_structuring_element(
+            imgproc::MORPH_ELLIPSE,
+            core::Size::new(dilate * 2 + 1, dilate * 2 + 1),
+            core::Point::new(-1, -1),
+        ).map_err(|e| e.to_string())?;
+        let mut dilated = core::Mat::default();
+        imgproc::dilate(&mask, &mut dilated, &kernel, core::Point::new(-1, -1), 1, core::BORDER_CONSTANT, core::Scalar::default()).map_err(|e| e.to_string())?;
+        mask = dilated;
+    }
+
+    if feather > 0.0 {
+        let mut blurred = core::Mat::default();
+        imgproc::gaussian_blur(&mask, &mut blurred, core::Size::new(0, 0), feather, 0.0, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+        mask = blurred;
+    }
+
+    Ok(mask)
+}
+
+// ランドマークと一般的な3D顔モデルからsolvePnPで顔の向きを推定
+fn estimate_head_pose(landmarks: &[core::Point2f], img_size: core::Size) -> Result<HeadPose, String> {
+    if landmarks.len() < 68 {
+        return Err("ランドマークが不足しています".to_string());
+    }
+
+    // 鼻先・顎・左目尻・右目尻・左口角・右口角（y軸上向きの汎用モデル）
+    let model_points = core::Vector::<core::Point3d>::from_iter([
+        core::Point3d::new(0.0, 0.0, 0.0),
+        core::Point3d::new(0.0, -330.0, -65.0),
+        core::Point3d::new(-225.0, 170.0, -135.0),
+        core::Point3d::new(225.0, 170.0, -135.0),
+        core::Point3d::new(-150.0, -150.0, -125.0),
+        core::Point3d::new(150.0, -150.0, -125.0),
+    ]);
+    let image_points = core::Vector::<core::Point2d>::from_iter(
+        [30, 8, 36, 45, 48, 54].iter().map(|&i| core::Point2d::new(landmarks[i].x as f64, landmarks[i].y as f64))
+    );
+
+    // カメラ行列は焦点距離=画像幅、主点=画像中心で近似
+    let focal = img_size.width as f64;
+    let cx = img_size.width as f64 / 2.0;
+    let cy = img_size.height as f64 / 2.0;
+    let camera_matrix = core::Mat::from_slice_2d(&[
+        [focal, 0.0, cx],
+        [0.0, focal, cy],
+        [0.0, 0.0, 1.0],
+    ]).map_err(|e| e.to_string())?;
+    let dist_coeffs = core::Mat::zeros(4, 1, core::CV_64F).map_err(|e| e.to_string())?
+        .to_mat().map_err(|e| e.to_string())?;
+
+    let mut rvec = core::Mat::default();
+    let mut tvec = core::Mat::default();
+    calib3d::solve_pnp(&model_points, &image_points, &camera_matrix, &dist_coeffs, &mut rvec, &mut tvec, false, calib3d::SOLVEPNP_ITERATIVE)
+        .map_err(|e| e.to_string())?;
+
+    let mut rmat = core::Mat::default();
+    calib3d::rodrigues(&rvec, &mut rmat, &mut core::Mat::default()).map_err(|e| e.to_string())?;
+
+    let r = |row: i32, col: i32| -> Result<f64, String> {
+        rmat.at_2d::<f64>(row, col).map(|v| *v).map_err(|e| e.to_string())
+    };
+
+    // 回転行列からオイラー角へ
+    let sy = (r(0, 0)?.powi(2) + r(1, 0)?.powi(2)).sqrt();
+    let mut pitch = r(2, 1)?.atan2(r(2, 2)?).to_degrees();
+    let yaw = (-r(2, 0)?).atan2(sy).to_degrees();
+    let roll = r(1, 0)?.atan2(r(0, 0)?).to_degrees();
+
+    // モデルはy軸上向き・画像はy軸下向きなのでpitchが±180付近になる分を戻す
+    if pitch > 90.0 {
+        pitch -= 180.0;
+    } else if pitch < -90.0 {
+        pitch += 180.0;
+    }
+
+    Ok(HeadPose { yaw, pitch, roll })
+}
+
+// 顔矩形内の画質指標を計算
+fn assess_face_quality(img: &core::Mat, rect: core::Rect) -> Result<FaceQuality, String> {
+    let roi = core::Mat::roi(img, rect).map_err(|e| e.to_string())?;
+    let mut face_img = core::Mat::default();
+    roi.copy_to(&mut face_img).map_err(|e| e.to_string())?;
+
+    let mut gray = core::Mat::default();
+    imgproc::cvt_color(&face_img, &mut gray, imgproc::COLOR_BGR2GRAY, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    let total = (gray.rows() * gray.cols()).max(1) as f64;
+
+    // 露出: 白飛び(>=250)と黒つぶれ(<=5)の割合
+    let mut highlights = core::Mat::default();
+    let mut shadows = core::Mat::default();
+    core::compare(&gray, &core::Scalar::all(250.0), &mut highlights, core::CMP_GE).map_err(|e| e.to_string())?;
+    core::compare(&gray, &core::Scalar::all(5.0), &mut shadows, core::CMP_LE).map_err(|e| e.to_string())?;
+    let highlight_clip_ratio = core::count_non_zero(&highlights).map_err(|e| e.to_string())? as f64 / total;
+    let shadow_clip_ratio = core::count_non_zero(&shadows).map_err(|e| e.to_string())? as f64 / total;
+
+    // 肌色の割合
+    let skin = create_skin_mask(&face_img)?;
+    let skin_coverage = core::count_non_zero(&skin).map_err(|e| e.to_string())? as f64 / total;
+
+    Ok(FaceQuality {
+        width: rect.width,
+        height: rect.height,
+        sharpness: laplacian_variance(&gray)?,
+        highlight_clip_ratio,
+        shadow_clip_ratio,
+        skin_coverage,
+    })
+}
+
+// シャープさの指標（ラプラシアンの分散）
+fn laplacian_variance(gray: &core::Mat) -> Result<f64, String> {
+    let mut laplacian = core::Mat::default();
+    imgproc::laplacian(gray, &mut laplacian, core::CV_64F, 1, 1.0, 0.0, core::BORDER_DEFAULT).map_err(|e| e.to_string())?;
+
+    let mut mean = core::Scalar::default();
+    let mut stddev = core::Scalar::default();
+    core::mean_std_dev(&laplacian, &mut mean, &mut stddev, &core::Mat::default()).map_err(|e| e.to_string())?;
+
+    Ok(stddev[0] * stddev[0])
+}
+
+// YCrCbで肌色範囲の画素を255にしたマスク（Cr: 133-173, Cb: 77-127）
+fn create_skin_mask(img: &core::Mat) -> Result<core::Mat, String> {
+    let mut ycrcb = core::Mat::default();
+    imgproc::cvt_color(img, &mut ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    let mut skin_mask = core::Mat::default();
+    let lower_skin = core::Scalar::new(0.0, 133.0, 77.0, 0.0);
+    let upper_skin = core::Scalar::new(255.0, 173.0, 127.0, 0.0);
+    core::in_range(&ycrcb, &lower_skin, &upper_skin, &mut skin_mask).map_err(|e| e.to_string())?;
+
+    Ok(skin_mask)
+}
+
+// 重複・入れ子の矩形を除外し、スコア（同点なら面積）の降順で返す
+fn non_max_suppression(mut candidates: Vec<DetectedFace>, options: &DetectOptions) -> Vec<DetectedFace> {
+    candidates.sort_by(|a, b| {
+        b.score.total_cmp(&a.score)
+            .then_with(|| b.rect.area().cmp(&a.rect.area()))
+    });
+
+    let mut kept: Vec<DetectedFace> = Vec::with_capacity(candidates.len());
+    for candidate in candidates {
+        let suppressed = kept.iter().any(|k| {
+            let (iou, containment) = rect_overlap(&k.rect, &candidate.rect);
+            iou > options.nms_iou_threshold || containment > options.containment_threshold
+        });
+        if !suppressed {
+            kept.push(candidate);
+        }
+    }
+
+    kept
+}
+
+// 2つの矩形の (IoU, 小さい方の面積に対する交差面積の割合) を返す
+fn rect_overlap(a: &core::Rect, b: &core::Rect) -> (f64, f64) {
+    let ix = (a.x + a.width).min(b.x + b.width) - a.x.max(b.x);
+    let iy = (a.y + a.height).min(b.y + b.height) - a.y.max(b.y);
+    if ix <= 0 || iy <= 0 {
+        return (0.0, 0.0);
+    }
+
+    let inter = (ix * iy) as f64;
+    let area_a = a.area() as f64;
+    let area_b = b.area() as f64;
+    let union = area_a + area_b - inter;
+    let min_area = area_a.min(area_b);
+
+    let iou = if union > 0.0 { inter / union } else { 0.0 };
+    let containment = if min_area > 0.0 { inter / min_area } else { 0.0 };
+    (iou, containment)
+}
+
+// キャンバスのマスクを作る。max_work_sizeより大きければ縮小してGrabCutし、マスクを元の解像度に戻す
+fn segment_canvas(img: &core::Mat, rect: core::Rect, options: &ExtractOptions) -> Result<(GrabCutState, core::Mat, MaskTimings), String> {
+    let longest = img.cols().max(img.rows());
+    let scale = match options.max_work_size {
+        Some(limit) if limit > 0 && longest > limit => limit as f64 / longest as f64,
+        _ => 1.0,
+    };
+
+    let start = std::time::Instant::now();
+    let mut state = if scale < 1.0 {
+        let work_size = core::Size::new(
+            ((img.cols() as f64 * scale).round() as i32).max(1),
+            ((img.rows() as f64 * scale).round() as i32).max(1),
+        );
+        let mut small = core::Mat::default();
+        imgproc::resize(img, &mut small, work_size, 0.0, 0.0, imgproc::INTER_AREA).map_err(|e| e.to_string())?;
+        let small_rect = core::Rect::new(
+            (rect.x as f64 * scale) as i32,
+            (rect.y as f64 * scale) as i32,
+            ((rect.width as f64 * scale) as i32).max(1),
+            ((rect.height as f64 * scale) as i32).max(1),
+        );
+        run_grab_cut(&small, small_rect, options)?
+    } else {
+        run_grab_cut(img, rect, options)?
+    };
+    state.scale = scale;
+    let work_mask = finalize_mask(&state.labels)?;
+    let grab_cut_ms = start.elapsed().as_secs_f64() * 1000.0;
+
+    let start = std::time::Instant::now();
+    let mask = if scale < 1.0 { upsample_mask(&work_mask, img, scale)? } else { work_mask };
+    let upsample_ms = if scale < 1.0 { start.elapsed().as_secs_f64() * 1000.0 } else { 0.0 };
+
+    let timings = MaskTimings {
+        grab_cut_ms,
+        upsample_ms,
+        work_width: state.labels.cols(),
+        work_height: state.labels.rows(),
+        iterations: state.iterations,
+    };
+    Ok((state, mask, timings))
+}
+
+// GrabCutのラベルからキャンバスの解像度のマスクを作る（refine_maskなどセッションから作り直す時用）
+fn labels_to_mask(labels: &core::Mat, scale: f64, canvas: &core::Mat) -> Result<core::Mat, String> {
+    let mask = finalize_mask(labels)?;
+    if scale < 1.0 {
+        upsample_mask(&mask, canvas, scale)
+    } else {
+        Ok(mask)
+    }
+}
+
+// 縮小解像度のマスクを元の画像をガイドにして拡大する（輪郭を画像のエッジに沿わせる）
+fn upsample_mask(mask: &core::Mat, guide_img: &core::Mat, scale: f64) -> Result<core::Mat, String> {
+    let size = guide_img.size().map_err(|e| e.to_string())?;
+    let mut mask_f32 = core::Mat::default();
+    mask.convert_to(&mut mask_f32, core::CV_32F, 1.0 / 255.0, 0.0).map_err(|e| e.to_string())?;
+    let mut upsampled = core::Mat::default();
+    imgproc::resize(&mask_f32, &mut upsampled, size, 0.0, 0.0, imgproc::INTER_LINEAR).map_err(|e| e.to_string())?;
+
+    let mut gray = core::Mat::default();
+    imgproc::cvt_color(guide_img, &mut gray, imgproc::COLOR_BGR2GRAY, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    let mut guide = core::Mat::default();
+    gray.convert_to(&mut guide, core::CV_32F, 1.0 / 255.0, 0.0).map_err(|e| e.to_string())?;
+
+    // 窓は縮小で失われた画素幅の2倍程度
+    let radius = ((2.0 / scale).ceil() as i32).max(2);
+    let refined = guided_filter(&guide, &upsampled, radius, 1e-3)?;
+
+    let mut refined_8u = core::Mat::default();
+    refined.convert_to(&mut refined_8u, core::CV_8U, 255.0, 0.0).map_err(|e| e.to_string())?;
+    let mut bin = core::Mat::default();
+    imgproc::threshold(&refined_8u, &mut bin, 127.0, 255.0, imgproc::THRESH_BINARY).map_err(|e| e.to_string())?;
+    Ok(bin)
+}
+
+// GrabCutの途中状態（refine_maskで同じモデルから再実行するために保持する）
+struct GrabCutState {
+    enhanced: core::Mat, // 前処理済みの画像（GrabCutへの入力）
+    labels: core::Mat,   // GC_FGD / GC_PR_FGD / GC_PR_BGD / GC_BGD のラベル
+    bgd: core::Mat,      // 背景のGMM
+    fgd: core::Mat,      // 前景のGMM
+    scale: f64,          // キャンバスに対する作業解像度の倍率（1なら縮小なし）
+    iterations: i32,     // 初回のGrabCutで反復した回数
 }
 
-fn create_high_quality_mask(img: &core::Mat, rect: core::Rect) -> Result<core::Mat, String> {
+fn run_grab_cut(img: &core::Mat, rect: core::Rect, options: &ExtractOptions) -> Result<GrabCutState, String> {
     let mut mask = core::Mat::new_size_with_default(img.size().map_err(|e| e.to_string())?, core::CV_8UC1, core::Scalar::all(imgproc::GC_PR_BGD as f64)).map_err(|e| e.to_string())?;
     let mut bgd = core::Mat::default();
     let mut fgd = core::Mat::default();
@@ -160,15 +881,64 @@ fn create_high_quality_mask(img: &core::Mat, rect: core::Rect) -> Result<core::M
     let mut enhanced = core::Mat::default();
     imgproc::cvt_color(&enhanced_lab, &mut enhanced, imgproc::COLOR_Lab2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
 
-    // GrabCut実行（反復回数を大幅に増やして精度最大化）
-    imgproc::grab_cut(&enhanced, &mut mask, rect, &mut bgd, &mut fgd, 25, imgproc::GC_INIT_WITH_RECT).map_err(|e| e.to_string())?;
+    // GrabCut実行（少しずつ反復し、前景がほとんど変わらなくなったら打ち切る）
+    let max_iterations = options.max_iterations.max(1);
+    let step = options.iteration_step.clamp(1, max_iterations);
+
+    imgproc::grab_cut(&enhanced, &mut mask, rect, &mut bgd, &mut fgd, step, imgproc::GC_INIT_WITH_RECT).map_err(|e| e.to_string())?;
+    let iterations = iterate_grab_cut(&enhanced, &mut mask, &mut bgd, &mut fgd, step, options)?;
+
+    Ok(GrabCutState { enhanced, labels: mask, bgd, fgd, scale: 1.0, iterations })
+}
+
+// 保存済みのGMMからGC_EVALでiteration_stepずつ反復し、前景の変化がconvergence_threshold未満になるか
+// max_iterationsに達したら止める（doneは反復済みの回数、戻り値は合計の反復回数）
+fn iterate_grab_cut(
+    img: &core::Mat,
+    labels: &mut core::Mat,
+    bgd: &mut core::Mat,
+    fgd: &mut core::Mat,
+    done: i32,
+    options: &ExtractOptions,
+) -> Result<i32, String> {
+    let max_iterations = options.max_iterations.max(1);
+    let step = options.iteration_step.clamp(1, max_iterations);
+    let total_pixels = (labels.rows() * labels.cols()).max(1) as f64;
+    let mut iterations = done;
+    let mut previous = grab_cut_foreground(labels)?;
+
+    while iterations < max_iterations {
+        let n = step.min(max_iterations - iterations);
+        imgproc::grab_cut(img, labels, core::Rect::default(), bgd, fgd, n, imgproc::GC_EVAL).map_err(|e| e.to_string())?;
+        iterations += n;
+
+        let current = grab_cut_foreground(labels)?;
+        let mut diff = core::Mat::default();
+        core::bitwise_xor(&previous, &current, &mut diff, &core::Mat::default()).map_err(|e| e.to_string())?;
+        let changed = core::count_non_zero(&diff).map_err(|e| e.to_string())? as f64 / total_pixels;
+        previous = current;
+        if changed < options.convergence_threshold {
+            break;
+        }
+    }
+
+    Ok(iterations)
+}
 
+// GrabCutのラベルのうち前景（GC_FGD / GC_PR_FGD）を255にした2値マスク
+fn grab_cut_foreground(mask: &core::Mat) -> Result<core::Mat, String> {
     let mut mask_fg = core::Mat::default();
     let mut mask_pr = core::Mat::default();
-    core::compare(&mask, &core::Scalar::all(imgproc::GC_FGD as f64), &mut mask_fg, core::CMP_EQ).map_err(|e| e.to_string())?;
-    core::compare(&mask, &core::Scalar::all(imgproc::GC_PR_FGD as f64), &mut mask_pr, core::CMP_EQ).map_err(|e| e.to_string())?;
+    core::compare(mask, &core::Scalar::all(imgproc::GC_FGD as f64), &mut mask_fg, core::CMP_EQ).map_err(|e| e.to_string())?;
+    core::compare(mask, &core::Scalar::all(imgproc::GC_PR_FGD as f64), &mut mask_pr, core::CMP_EQ).map_err(|e| e.to_string())?;
     let mut bin = core::Mat::default();
     core::bitwise_or(&mask_fg, &mask_pr, &mut bin, &core::Mat::default()).map_err(|e| e.to_string())?;
+    Ok(bin)
+}
+
+// GrabCutのラベルから前景を取り出し、モルフォロジーで整えた2値マスクにする
+fn finalize_mask(mask: &core::Mat) -> Result<core::Mat, String> {
+    let bin = grab_cut_foreground(mask)?;
 
     // モルフォロジー演算を強化してより精密に
     let mut temp = core::Mat::default();
@@ -199,132 +969,598 @@ fn create_high_quality_mask(img: &core::Mat, rect: core::Rect) -> Result<core::M
     Ok(final_mask)
 }
 
-fn apply_mask_and_encode_parallel(img: &core::Mat, mask: &core::Mat) -> Result<String, String> {
-    let width = img.cols() as usize;
-    let height = img.rows() as usize;
-    
-    // 1. 空のMatを作成
-    let mut final_mat = unsafe {
-        core::Mat::new_rows_cols(
-            height as i32,
-            width as i32,
-            core::CV_8UC4
-        ).map_err(|e| e.to_string())?
-    };
-
-    // 2. メモリ領域を借りる
-    let mat_data = final_mat.data_bytes_mut().map_err(|e| e.to_string())?;
-
-    // 3. 並列書き込み
-    mat_data.par_chunks_exact_mut(width * 4)
-        .enumerate()
-        .for_each(|(y, row_slice)| {
-            for x in 0..width {
-                // ★修正箇所: .map(|p| *p) を使って「参照」ではなく「値」を取り出す
-                // これで unwrap_or に一時変数の参照を渡す必要がなくなり、エラーが消える
-                let color_pix = img.at_2d::<core::Vec3b>(y as i32, x as i32)
-                    .map(|p| *p) // 参照から値へコピー
-                    .unwrap_or(core::Vec3b::all(0)); // デフォルト値も値として渡す
-
-                let alpha_val = mask.at_2d::<u8>(y as i32, x as i32)
-                    .map(|p| *p) // 参照から値へコピー
-                    .unwrap_or(0);
-
-                let offset = x * 4;
-                row_slice[offset + 0] = color_pix[0]; // B
-                row_slice[offset + 1] = color_pix[1]; // G
-                row_slice[offset + 2] = color_pix[2]; // R
-                row_slice[offset + 3] = alpha_val;    // A (ここは *alpha_val じゃなくて alpha_val になる)
-            }
-        });
+// 切り抜き画像をPNG(base64)にする。mattingがあれば縁を半透明のアルファにする
+fn encode_cutout(img: &core::Mat, mask: &core::Mat, matting: Option<&MattingOptions>) -> Result<String, String> {
+    let (colors, alpha) = finish_cutout(img, mask, matting)?;
+    apply_mask_and_encode_parallel(&colors, &alpha)
+}
+
+// 切り抜きの色とアルファを決める（mattingが無ければマスクをそのままアルファにする）
+fn finish_cutout(img: &core::Mat, mask: &core::Mat, matting: Option<&MattingOptions>) -> Result<(core::Mat, core::Mat), String> {
+    match matting {
+        Some(options) => alpha_matting(img, mask, options),
+        None => Ok((img.clone(), mask.clone())),
+    }
+}
 
-    // 4. エンコード
+fn encode_png_base64(img: &core::Mat) -> Result<String, String> {
     let mut buf = core::Vector::<u8>::new();
-    imgcodecs::imencode(".png", &final_mat, &mut buf, &core::Vector::new())
+    imgcodecs::imencode(".png", img, &mut buf, &core::Vector::new())
         .map_err(|e| e.to_string())?;
-
     Ok(general_purpose::STANDARD.encode(buf.as_slice()))
 }
 
-#[tauri::command]
-fn greet(name: &str) -> String {
-    format!("Hello, {}! You've been greeted from Rust!", name)
+// GrabCutのラベルを色分けする（確実な前景: 白 / 前景らしい: 緑 / 背景らしい: 青 / 確実な背景: 黒）
+// 縮小してGrabCutした場合はsizeまで最近傍で拡大する
+fn visualize_grab_cut_labels(labels: &core::Mat, size: core::Size) -> Result<core::Mat, String> {
+    let mut vis = core::Mat::new_size_with_default(labels.size().map_err(|e| e.to_string())?, core::CV_8UC3, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+    let colors = [
+        (imgproc::GC_FGD, core::Scalar::new(255.0, 255.0, 255.0, 0.0)),
+        (imgproc::GC_PR_FGD, core::Scalar::new(0.0, 200.0, 0.0, 0.0)),
+        (imgproc::GC_PR_BGD, core::Scalar::new(160.0, 60.0, 0.0, 0.0)),
+        (imgproc::GC_BGD, core::Scalar::new(0.0, 0.0, 0.0, 0.0)),
+    ];
+    for (label, color) in colors {
+        let mut region = core::Mat::default();
+        core::compare(labels, &core::Scalar::all(label as f64), &mut region, core::CMP_EQ).map_err(|e| e.to_string())?;
+        vis.set_to(&color, &region).map_err(|e| e.to_string())?;
+    }
+    if vis.size().map_err(|e| e.to_string())? != size {
+        let mut resized = core::Mat::default();
+        imgproc::resize(&vis, &mut resized, size, 0.0, 0.0, imgproc::INTER_NEAREST).map_err(|e| e.to_string())?;
+        vis = resized;
+    }
+    Ok(vis)
 }
 
-#[derive(serde::Serialize)]
-struct FaceSwapResult {
-    base64: String,  // 合成結果画像
-    color_correction_strength: f64,  // 使用された色補正強度（0.0-1.0）
+// マスクの一番大きい外形を多角形で返す（offsetを足して元画像座標にする）
+fn mask_contour(mask: &core::Mat, offset: core::Point) -> Result<Vec<[i32; 2]>, String> {
+    let mut bin = core::Mat::default();
+    imgproc::threshold(mask, &mut bin, 127.0, 255.0, imgproc::THRESH_BINARY).map_err(|e| e.to_string())?;
+    let mut contours = core::Vector::<core::Vector<core::Point>>::new();
+    imgproc::find_contours(&bin, &mut contours, imgproc::RETR_EXTERNAL, imgproc::CHAIN_APPROX_SIMPLE, offset).map_err(|e| e.to_string())?;
+
+    let mut largest: Option<(f64, core::Vector<core::Point>)> = None;
+    for contour in contours {
+        let area = imgproc::contour_area(&contour, false).map_err(|e| e.to_string())?;
+        if largest.as_ref().is_none_or(|(best, _)| area > *best) {
+            largest = Some((area, contour));
+        }
+    }
+    Ok(largest.map(|(_, contour)| contour.iter().map(|p| [p.x, p.y]).collect()).unwrap_or_default())
 }
 
-#[tauri::command]
-fn face_swap(source_path: String, target_path: String, color_correction: Option<f64>) -> Result<FaceSwapResult, String> {
-    opencv::core::set_use_optimized(true).ok();
-    opencv::core::set_num_threads(0).ok();
+// 2値マスクからトライマップを作り、未知の帯だけガイデッドフィルタで半透明のアルファを推定する
+// 戻り値は（背景色を除いた画像, 8bitアルファ）
+fn alpha_matting(img: &core::Mat, mask: &core::Mat, options: &MattingOptions) -> Result<(core::Mat, core::Mat), String> {
+    // 1. トライマップ（確実な前景 / 確実な背景 / その間の未知の帯）
+    let erode_size = options.foreground_erode.max(0) * 2 + 1;
+    let dilate_size = options.background_dilate.max(0) * 2 + 1;
+    let k_erode = imgproc::get_structuring_element(imgproc::MORPH_ELLIPSE, core::Size::new(erode_size, erode_size), core::Point::new(-1, -1)).map_err(|e| e.to_string())?;
+    let k_dilate = imgproc::get_structuring_element(imgproc::MORPH_ELLIPSE, core::Size::new(dilate_size, dilate_size), core::Point::new(-1, -1)).map_err(|e| e.to_string())?;
+    let mut sure_fg = core::Mat::default();
+    let mut maybe_fg = core::Mat::default();
+    imgproc::erode(mask, &mut sure_fg, &k_erode, core::Point::new(-1, -1), 1, core::BORDER_CONSTANT, core::Scalar::default()).map_err(|e| e.to_string())?;
+    imgproc::dilate(mask, &mut maybe_fg, &k_dilate, core::Point::new(-1, -1), 1, core::BORDER_CONSTANT, core::Scalar::default()).map_err(|e| e.to_string())?;
+    let mut sure_bg = core::Mat::default();
+    core::bitwise_not(&maybe_fg, &mut sure_bg, &core::Mat::default()).map_err(|e| e.to_string())?;
 
-    // 画像読み込み
-    let source_img = imgcodecs::imread(&source_path, imgcodecs::IMREAD_COLOR)
-        .map_err(|_| "ソース画像の読み込みに失敗")?;
-    let target_img = imgcodecs::imread(&target_path, imgcodecs::IMREAD_COLOR)
-        .map_err(|_| "ターゲット画像の読み込みに失敗")?;
+    // 2. 画像の輝度をガイドにしてマスクをフィルタ（髪の毛など細い構造に沿ってアルファが付く）
+    let mut gray = core::Mat::default();
+    imgproc::cvt_color(img, &mut gray, imgproc::COLOR_BGR2GRAY, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    let mut guide = core::Mat::default();
+    let mut mask_f32 = core::Mat::default();
+    gray.convert_to(&mut guide, core::CV_32F, 1.0 / 255.0, 0.0).map_err(|e| e.to_string())?;
+    mask.convert_to(&mut mask_f32, core::CV_32F, 1.0 / 255.0, 0.0).map_err(|e| e.to_string())?;
+    let soft = guided_filter(&guide, &mask_f32, options.radius.max(1), options.eps)?;
 
-    // 顔検出
-    let source_faces = detect_faces(&source_img)?;
-    let target_faces = detect_faces(&target_img)?;
+    // 0-1をはみ出した値は8bit変換で飽和する
+    let mut alpha = core::Mat::default();
+    soft.convert_to(&mut alpha, core::CV_8U, 255.0, 0.0).map_err(|e| e.to_string())?;
+    alpha.set_to(&core::Scalar::all(255.0), &sure_fg).map_err(|e| e.to_string())?;
+    alpha.set_to(&core::Scalar::all(0.0), &sure_bg).map_err(|e| e.to_string())?;
 
-    if source_faces.is_empty() {
-        return Err("ソース画像に顔が検出されませんでした".to_string());
+    if !options.decontaminate {
+        return Ok((img.clone(), alpha));
     }
-    if target_faces.is_empty() {
-        return Err("ターゲット画像に顔が検出されませんでした".to_string());
+
+    // 3. 縁の色から背景色を取り除く: I = αF + (1-α)B より F = (I - (1-α)B) / α
+    // 背景色Bは近くの確実な背景画素の重み付き平均で推定
+    let mut img_f32 = core::Mat::default();
+    img.convert_to(&mut img_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
+    let mut bg_only = core::Mat::new_size_with_default(img.size().map_err(|e| e.to_string())?, core::CV_32FC3, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+    img_f32.copy_to_masked(&mut bg_only, &sure_bg).map_err(|e| e.to_string())?;
+    let mut bg_weight = core::Mat::default();
+    sure_bg.convert_to(&mut bg_weight, core::CV_32F, 1.0 / 255.0, 0.0).map_err(|e| e.to_string())?;
+
+    let mut bg_sum = core::Mat::default();
+    let mut weight_sum = core::Mat::default();
+    imgproc::gaussian_blur(&bg_only, &mut bg_sum, core::Size::new(0, 0), options.background_sigma, options.background_sigma, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    imgproc::gaussian_blur(&bg_weight, &mut weight_sum, core::Size::new(0, 0), options.background_sigma, options.background_sigma, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    let mut weight_3ch = core::Mat::default();
+    let mut weight_channels = core::Vector::<core::Mat>::new();
+    for _ in 0..3 {
+        weight_channels.push(weight_sum.clone());
     }
+    core::merge(&weight_channels, &mut weight_3ch).map_err(|e| e.to_string())?;
+    let mut weight_safe = core::Mat::default();
+    core::max(&weight_3ch, &core::Scalar::all(1e-6), &mut weight_safe).map_err(|e| e.to_string())?;
+    let mut background = core::Mat::default();
+    core::divide2(&bg_sum, &weight_safe, &mut background, 1.0, -1).map_err(|e| e.to_string())?;
 
-    let source_face = source_faces.get(0).map_err(|e| e.to_string())?;
-    let target_face = target_faces.get(0).map_err(|e| e.to_string())?;
+    let mut alpha_f32 = core::Mat::default();
+    alpha.convert_to(&mut alpha_f32, core::CV_32F, 1.0 / 255.0, 0.0).map_err(|e| e.to_string())?;
+    let mut alpha_channels = core::Vector::<core::Mat>::new();
+    for _ in 0..3 {
+        alpha_channels.push(alpha_f32.clone());
+    }
+    let mut alpha_3ch = core::Mat::default();
+    core::merge(&alpha_channels, &mut alpha_3ch).map_err(|e| e.to_string())?;
 
-    // ソース顔を検出矩形で切り抜き（顔だけ）
-    let source_face_roi = core::Mat::roi(&source_img, source_face).map_err(|e| e.to_string())?;
-    let mut source_face_img = core::Mat::default();
-    source_face_roi.copy_to(&mut source_face_img).map_err(|e| e.to_string())?;
+    let mut inv_alpha = core::Mat::default();
+    core::subtract(&core::Scalar::all(1.0), &alpha_3ch, &mut inv_alpha, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    let mut bg_part = core::Mat::default();
+    core::multiply(&inv_alpha, &background, &mut bg_part, 1.0, -1).map_err(|e| e.to_string())?;
+    let mut numerator = core::Mat::default();
+    core::subtract(&img_f32, &bg_part, &mut numerator, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    // αが小さいと誤差が増幅されるので下限を設ける
+    let mut alpha_safe = core::Mat::default();
+    core::max(&alpha_3ch, &core::Scalar::all(0.1), &mut alpha_safe).map_err(|e| e.to_string())?;
+    let mut foreground_f32 = core::Mat::default();
+    core::divide2(&numerator, &alpha_safe, &mut foreground_f32, 1.0, -1).map_err(|e| e.to_string())?;
+    let mut foreground = core::Mat::default();
+    foreground_f32.convert_to(&mut foreground, core::CV_8U, 1.0, 0.0).map_err(|e| e.to_string())?;
 
-    // ターゲット顔も切り抜き（色補正用）
-    let target_face_roi = core::Mat::roi(&target_img, target_face).map_err(|e| e.to_string())?;
-    let mut target_face_img = core::Mat::default();
-    target_face_roi.copy_to(&mut target_face_img).map_err(|e| e.to_string())?;
+    // 未知の帯で、近くに背景画素があり、見えている画素だけ置き換える
+    let mut not_fg = core::Mat::default();
+    core::bitwise_not(&sure_fg, &mut not_fg, &core::Mat::default()).map_err(|e| e.to_string())?;
+    let mut has_bg = core::Mat::default();
+    core::compare(&weight_sum, &core::Scalar::all(0.01), &mut has_bg, core::CMP_GT).map_err(|e| e.to_string())?;
+    let mut visible = core::Mat::default();
+    core::compare(&alpha, &core::Scalar::all(0.0), &mut visible, core::CMP_GT).map_err(|e| e.to_string())?;
+    let mut band = core::Mat::default();
+    core::bitwise_and(&not_fg, &maybe_fg, &mut band, &core::Mat::default()).map_err(|e| e.to_string())?;
+    let mut band_bg = core::Mat::default();
+    core::bitwise_and(&band, &has_bg, &mut band_bg, &core::Mat::default()).map_err(|e| e.to_string())?;
+    let mut update = core::Mat::default();
+    core::bitwise_and(&band_bg, &visible, &mut update, &core::Mat::default()).map_err(|e| e.to_string())?;
 
-    // 色補正強度を自動計算（肌色の差に基づく）
-    let auto_correction_strength = if color_correction.is_none() {
-        calculate_color_correction_strength(&source_face_img, &target_face_img)?
+    let mut colors = img.clone();
+    foreground.copy_to_masked(&mut colors, &update).map_err(|e| e.to_string())?;
+
+    Ok((colors, alpha))
+}
+
+// グレースケールのガイド画像によるガイデッドフィルタ（He et al.）。入力はどちらもCV_32F
+fn guided_filter(guide: &core::Mat, src: &core::Mat, radius: i32, eps: f64) -> Result<core::Mat, String> {
+    let ksize = core::Size::new(radius * 2 + 1, radius * 2 + 1);
+    let box_mean = |m: &core::Mat| -> Result<core::Mat, String> {
+        let mut out = core::Mat::default();
+        imgproc::box_filter(m, &mut out, -1, ksize, core::Point::new(-1, -1), true, core::BORDER_REFLECT).map_err(|e| e.to_string())?;
+        Ok(out)
+    };
+    let mul = |a: &core::Mat, b: &core::Mat| -> Result<core::Mat, String> {
+        let mut out = core::Mat::default();
+        core::multiply(a, b, &mut out, 1.0, -1).map_err(|e| e.to_string())?;
+        Ok(out)
+    };
+
+    let mean_i = box_mean(guide)?;
+    let mean_p = box_mean(src)?;
+    let corr_ip = box_mean(&mul(guide, src)?)?;
+    let corr_ii = box_mean(&mul(guide, guide)?)?;
+
+    let mut var_i = core::Mat::default();
+    core::subtract(&corr_ii, &mul(&mean_i, &mean_i)?, &mut var_i, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    let mut cov_ip = core::Mat::default();
+    core::subtract(&corr_ip, &mul(&mean_i, &mean_p)?, &mut cov_ip, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+
+    let mut var_eps = core::Mat::default();
+    core::add(&var_i, &core::Scalar::all(eps), &mut var_eps, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    let mut a = core::Mat::default();
+    core::divide2(&cov_ip, &var_eps, &mut a, 1.0, -1).map_err(|e| e.to_string())?;
+    let mut b = core::Mat::default();
+    core::subtract(&mean_p, &mul(&a, &mean_i)?, &mut b, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+
+    let mut q = core::Mat::default();
+    core::add(&mul(&box_mean(&a)?, guide)?, &box_mean(&b)?, &mut q, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    Ok(q)
+}
+
+fn apply_mask_and_encode_parallel(img: &core::Mat, mask: &core::Mat) -> Result<String, String> {
+    let bgra = merge_alpha(img, mask)?;
+    encode_png_base64(&bgra)
+}
+
+// BGR画像（CV_8UC3）とマスク（CV_8UC1）からBGRA画像を作る（RGBAを出力する処理はすべてこれを使う）
+// 入力はROIなど非連続なMatでもよい。行ごとのスライスにしてから並列に詰め替える
+fn merge_alpha(img: &core::Mat, mask: &core::Mat) -> Result<core::Mat, String> {
+    if img.typ() != core::CV_8UC3 || mask.typ() != core::CV_8UC1 {
+        return Err("BGRA化できるのは8bitのBGR画像と8bitのマスクだけです".to_string());
+    }
+    let size = img.size().map_err(|e| e.to_string())?;
+    if mask.size().map_err(|e| e.to_string())? != size {
+        return Err("画像とマスクのサイズが違います".to_string());
+    }
+    if size.width == 0 || size.height == 0 {
+        return Ok(core::Mat::default());
+    }
+
+    // 1. 入力の各行をスライスとして借りる（行のstepはOpenCV側で考慮される）
+    let color_rows = (0..size.height)
+        .map(|y| img.at_row::<core::Vec3b>(y))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    let alpha_rows = (0..size.height)
+        .map(|y| mask.at_row::<u8>(y))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+
+    // 2. 出力は新規確保なので連続。全画素を書き込むので初期化は不要
+    let mut bgra = unsafe {
+        core::Mat::new_rows_cols(size.height, size.width, core::CV_8UC4).map_err(|e| e.to_string())?
+    };
+    let width = size.width as usize;
+    bgra.data_bytes_mut().map_err(|e| e.to_string())?
+        .par_chunks_exact_mut(width * 4)
+        .zip(color_rows.par_iter().zip(alpha_rows.par_iter()))
+        .for_each(|(out_row, (color_row, alpha_row))| {
+            for ((out, color), alpha) in out_row.chunks_exact_mut(4).zip(color_row.iter()).zip(alpha_row.iter()) {
+                out[0] = color[0]; // B
+                out[1] = color[1]; // G
+                out[2] = color[2]; // R
+                out[3] = *alpha;   // A
+            }
+        });
+
+    Ok(bgra)
+}
+
+#[tauri::command]
+fn greet(name: &str) -> String {
+    format!("Hello, {}! You've been greeted from Rust!", name)
+}
+
+#[derive(serde::Serialize)]
+struct FaceSwapResult {
+    base64: String,  // 合成結果画像
+    color_correction_strength: f64,  // 使用された色補正強度（0.0-1.0）
+    source_quality: FaceQuality,     // ソース顔の画質（元解像度）
+    target_quality: FaceQuality,     // ターゲット顔の画質
+    texture_adjustment: Option<TextureAdjustment>, // シャープさ・ノイズ合わせで適用した量
+    inpainted_pixels: i32,           // 修復した画素数
+    source_pose: Option<HeadPose>,   // ソース顔の向き
+    target_pose: Option<HeadPose>,   // ターゲット顔の向き
+    mirrored: bool,                  // ソースを左右反転して使ったか
+    used_source_alpha: bool,         // ソースのアルファを合成マスクに使ったか
+    warnings: Vec<String>,           // 合成はしたが結果が不自然になりそうな点
+}
+
+// face swapの設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct SwapOptions {
+    mode: SwapMode,           // 顔だけ入れ替えるか、髪ごと頭を入れ替えるか
+    check_pose: bool,         // 顔の向きを比較する
+    max_yaw_difference: f64,  // これ以上yawが違えば警告（度）
+    strict_pose: bool,        // 警告ではなくエラーにする
+    auto_mirror: bool,        // 反転した方が向きが近ければソースを左右反転
+    min_scale_ratio: f64,     // ソース顔の幅がターゲットのこの割合未満なら拡大しすぎの警告
+    min_sharpness_ratio: f64, // リサイズ後のソースのシャープさがターゲットのこの割合未満なら警告
+    match_texture: bool,          // シャープさ・ノイズをターゲットに合わせる
+    blur_sigma: Option<f64>,      // ぼかし量を指定（Noneなら自動）
+    sharpen_amount: Option<f64>,  // シャープ化の量を指定（Noneなら自動）
+    noise_sigma: Option<f64>,     // 追加するノイズの標準偏差を指定（Noneなら自動）
+    inpaint: Option<InpaintOptions>, // 元の顔の取り残し部分の修復（Noneなら顔モードは修復なし、頭モードはデフォルト設定）
+    keep_target_eyes: bool,   // ターゲットの目を残す（視線を保つ）
+    keep_target_mouth: bool,  // ターゲットの口の中を残す（開いた口・歯を保つ）
+    feature_dilate: i32,      // 残す部位を広げる幅（px）
+    feature_feather: f64,     // 残す部位の縁のぼかし（σ）
+    use_source_alpha: bool,       // ソースがRGBA（process_faceの出力など）ならアルファを合成マスクに使う
+    skip_source_detection: bool,  // RGBAのソースでは顔検出せず、切り抜き時の余白から顔の位置を推定
+    source_library_id: Option<String>, // 指定するとsource_pathの代わりにライブラリの顔を使う
+    extract: ExtractOptions,  // 頭モードの切り抜き範囲（RGBAのソースの顔位置の推定にも使う）
+}
+
+// 修復（インペイント）の設定
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct InpaintOptions {
+    method: InpaintMethod,
+    radius: f64,   // Telea/NSの参照半径（px）
+    band: i32,     // 新しいマスクの縁からこの幅（px）の範囲だけ修復（0なら制限なし）
+    dilate: i32,   // 修復範囲をこの幅（px）だけ広げて輪郭のにじみも消す
+}
+
+// 修復アルゴリズム
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum InpaintMethod {
+    Telea,     // 高速マーチング法
+    Ns,        // Navier-Stokes
+    Exemplar,  // パッチベース（xphotoのShift-Map、遅いが広い領域向き）
+}
+
+impl Default for InpaintOptions {
+    fn default() -> Self {
+        InpaintOptions {
+            method: InpaintMethod::Telea,
+            radius: 5.0,
+            band: 0,
+            dilate: 2,
+        }
+    }
+}
+
+// シャープさ・ノイズ合わせの推定値と適用量
+#[derive(serde::Serialize, Clone, Copy)]
+struct TextureAdjustment {
+    source_sharpness: f64,
+    target_sharpness: f64,
+    source_noise: f64,
+    target_noise: f64,
+    blur_sigma: f64,      // 適用したガウスぼかしのσ（0なら無し）
+    sharpen_amount: f64,  // 適用したアンシャープマスクの量（0なら無し）
+    noise_sigma: f64,     // 追加したノイズの標準偏差（0なら無し）
+}
+
+// 入れ替える範囲
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum SwapMode {
+    Face, // 顔の内側だけ（楕円マスク）
+    Head, // 髪を含む頭全体（GrabCutマスク）
+}
+
+impl Default for SwapOptions {
+    fn default() -> Self {
+        SwapOptions {
+            mode: SwapMode::Face,
+            check_pose: true,
+            max_yaw_difference: 20.0,
+            strict_pose: false,
+            auto_mirror: false,
+            min_scale_ratio: 0.75,
+            min_sharpness_ratio: 0.5,
+            match_texture: true,
+            blur_sigma: None,
+            sharpen_amount: None,
+            noise_sigma: None,
+            inpaint: None,
+            keep_target_eyes: false,
+            keep_target_mouth: false,
+            feature_dilate: 3,
+            feature_feather: 3.0,
+            use_source_alpha: true,
+            skip_source_detection: false,
+            source_library_id: None,
+            extract: ExtractOptions::for_swap(),
+        }
+    }
+}
+
+#[tauri::command]
+fn face_swap(app: tauri::AppHandle, source_path: String, target_path: String, color_correction: Option<f64>, detect_options: Option<DetectOptions>, swap_options: Option<SwapOptions>) -> Result<FaceSwapResult, String> {
+    opencv::core::set_use_optimized(true).ok();
+    opencv::core::set_num_threads(0).ok();
+
+    let swap_options = swap_options.unwrap_or_default();
+    let mut warnings: Vec<String> = Vec::new();
+
+    // ライブラリの顔が指定されていればその切り抜き画像をソースにする
+    let source_path = match &swap_options.source_library_id {
+        Some(id) => library_image_path(&app, id)?,
+        None => source_path,
+    };
+
+    // 画像読み込み（ソースは切り抜き済みのRGBAかもしれないのでアルファも読む）
+    let (mut source_img, mut source_alpha) = read_with_alpha(&source_path)?;
+    if !swap_options.use_source_alpha {
+        source_alpha = None;
+    }
+    let target_img = imgcodecs::imread(&target_path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "ターゲット画像の読み込みに失敗")?;
+
+    // 顔検出（スコア順なので先頭が最も確からしい顔）
+    let detect_options = detect_options.unwrap_or_default();
+    let target_faces = detect_faces(&target_img, &detect_options)?.faces;
+    if target_faces.is_empty() {
+        return Err("ターゲット画像に顔が検出されませんでした".to_string());
+    }
+
+    // RGBAのソースは検出できなくても切り抜き時の余白から顔の位置が分かる
+    let source_faces = if source_alpha.is_some() && swap_options.skip_source_detection {
+        Vec::new()
     } else {
-        color_correction.unwrap()
+        detect_faces(&source_img, &detect_options)?.faces
     };
+    let mut source_face = match (source_faces.first(), &source_alpha) {
+        (Some(face), _) => face.rect,
+        (None, Some(_)) => face_rect_in_canvas(source_img.size().map_err(|e| e.to_string())?, &swap_options.extract),
+        (None, None) => return Err("ソース画像に顔が検出されませんでした".to_string()),
+    };
+    let target_face = target_faces[0].rect;
 
-    // ソース顔をターゲット顔のサイズにリサイズ
-    let mut resized_face = core::Mat::default();
-    imgproc::resize(
-        &source_face_img, 
-        &mut resized_face, 
-        core::Size::new(target_face.width, target_face.height), 
-        0.0, 0.0, 
-        imgproc::INTER_LANCZOS4
-    ).map_err(|e| e.to_string())?;
+    // 顔の向きを比較（正面の顔を斜めの顔に貼ると破綻するため）
+    let mut facemark = LazyFacemark::new();
+    let mut source_pose = None;
+    let mut target_pose = None;
+    let mut mirrored = false;
+    if swap_options.check_pose {
+        let poses = facemark.landmarks(&source_img, source_face).and_then(|source_landmarks| {
+            let target_landmarks = facemark.landmarks(&target_img, target_face)?;
+            let source_size = source_img.size().map_err(|e| e.to_string())?;
+            let target_size = target_img.size().map_err(|e| e.to_string())?;
+            Ok((
+                estimate_head_pose(&source_landmarks, source_size)?,
+                estimate_head_pose(&target_landmarks, target_size)?,
+            ))
+        });
+
+        match poses {
+            Ok((mut sp, tp)) => {
+                // 左右反転するとyawとrollの符号が反転する
+                if swap_options.auto_mirror && (-sp.yaw - tp.yaw).abs() < (sp.yaw - tp.yaw).abs() {
+                    let mut flipped = core::Mat::default();
+                    core::flip(&source_img, &mut flipped, 1).map_err(|e| e.to_string())?;
+                    source_face.x = flipped.cols() - source_face.x - source_face.width;
+                    source_img = flipped;
+                    if let Some(alpha) = &source_alpha {
+                        let mut flipped_alpha = core::Mat::default();
+                        core::flip(alpha, &mut flipped_alpha, 1).map_err(|e| e.to_string())?;
+                        source_alpha = Some(flipped_alpha);
+                    }
+                    sp.yaw = -sp.yaw;
+                    sp.roll = -sp.roll;
+                    mirrored = true;
+                }
 
-    // 照明補正: ヒストグラムマッチングで明暗を合わせる
-    let mut illumination_matched = core::Mat::default();
-    match_illumination(&resized_face, &target_face_img, &mut illumination_matched)?;
+                let yaw_diff = (sp.yaw - tp.yaw).abs();
+                if yaw_diff > swap_options.max_yaw_difference {
+                    let message = format!("顔の向きが大きく異なります（yawの差 {:.1}°）", yaw_diff);
+                    if swap_options.strict_pose {
+                        return Err(message);
+                    }
+                    warnings.push(message);
+                }
 
-    // 色補正: ソース顔の色をターゲット顔に合わせる
-    let mut color_corrected = core::Mat::default();
-    match_color(&illumination_matched, &target_face_img, &mut color_corrected, auto_correction_strength)?;
+                source_pose = Some(sp);
+                target_pose = Some(tp);
+            }
+            Err(e) => {
+                if swap_options.strict_pose {
+                    return Err(format!("顔の向きを推定できませんでした: {}", e));
+                }
+                warnings.push(format!("顔の向きチェックをスキップしました: {}", e));
+            }
+        }
+    }
+
+    // ターゲットの目・口を残す場合は、その部位を合成マスクから引く
+    let mut keep_regions = Vec::new();
+    if swap_options.keep_target_eyes {
+        keep_regions.extend([LANDMARK_RIGHT_EYE, LANDMARK_LEFT_EYE]);
+    }
+    if swap_options.keep_target_mouth {
+        keep_regions.push(LANDMARK_INNER_MOUTH);
+    }
+    let keep_mask = if keep_regions.is_empty() {
+        None
+    } else {
+        let target_landmarks = facemark.landmarks(&target_img, target_face)?;
+        let target_size = target_img.size().map_err(|e| e.to_string())?;
+        Some(landmark_region_mask(target_size, &target_landmarks, &keep_regions, swap_options.feature_dilate, swap_options.feature_feather)?)
+    };
+
+    // 画質チェック（小さい・ぼやけたソースはLANCZOS4で拡大しても目立つ）
+    let source_quality = assess_face_quality(&source_img, source_face)?;
+    let target_quality = assess_face_quality(&target_img, target_face)?;
+    let scale_ratio = source_face.width as f64 / target_face.width as f64;
+    if scale_ratio < swap_options.min_scale_ratio {
+        warnings.push(format!("ソース顔がターゲットより小さいため{:.1}倍に拡大されます", 1.0 / scale_ratio));
+    }
+
+    // ソース顔を検出矩形で切り抜き（顔だけ）
+    let source_face_roi = core::Mat::roi(&source_img, source_face).map_err(|e| e.to_string())?;
+    let mut source_face_img = core::Mat::default();
+    source_face_roi.copy_to(&mut source_face_img).map_err(|e| e.to_string())?;
 
-    // 楽円マスクを作成（顔全体を滑らかに合成）
-    let mask = create_ellipse_mask(target_face.width, target_face.height)?;
+    // ターゲット顔も切り抜き（色補正用）
+    let target_face_roi = core::Mat::roi(&target_img, target_face).map_err(|e| e.to_string())?;
+    let mut target_face_img = core::Mat::default();
+    target_face_roi.copy_to(&mut target_face_img).map_err(|e| e.to_string())?;
+
+    // 色補正強度を自動計算（肌色の差に基づく）
+    let auto_correction_strength = if color_correction.is_none() {
+        calculate_color_correction_strength(&source_face_img, &target_face_img)?
+    } else {
+        color_correction.unwrap()
+    };
 
     // ターゲット画像のコピーを作成
     let mut result = target_img.clone();
+    let mut texture_adjustment = None;
+    let mut inpainted_pixels = 0;
+
+    // RGBAのソースはアルファをそのまま合成マスクに使う（配置は頭モードと同じ）
+    let mode = if source_alpha.is_some() { SwapMode::Head } else { swap_options.mode };
+    match mode {
+        SwapMode::Face => {
+            // ソース顔をターゲット顔のサイズにリサイズ
+            let mut resized_face = core::Mat::default();
+            imgproc::resize(
+                &source_face_img, 
+                &mut resized_face, 
+                core::Size::new(target_face.width, target_face.height), 
+                0.0, 0.0, 
+                imgproc::INTER_LANCZOS4
+            ).map_err(|e| e.to_string())?;
+
+            // 拡大後のシャープさで比較（解像度の違いを打ち消すため）
+            let mut resized_gray = core::Mat::default();
+            imgproc::cvt_color(&resized_face, &mut resized_gray, imgproc::COLOR_BGR2GRAY, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+            let resized_sharpness = laplacian_variance(&resized_gray)?;
+            if target_quality.sharpness > 0.0 && resized_sharpness / target_quality.sharpness < swap_options.min_sharpness_ratio {
+                warnings.push(format!(
+                    "ソース顔がターゲットよりぼやけています（シャープさ {:.0} / {:.0}）",
+                    resized_sharpness, target_quality.sharpness
+                ));
+            }
+
+            // 照明補正: ヒストグラムマッチングで明暗を合わせる
+            let mut illumination_matched = core::Mat::default();
+            match_illumination(&resized_face, &target_face_img, &mut illumination_matched)?;
+
+            // 色補正: ソース顔の色をターゲット顔に合わせる
+            let mut color_corrected = core::Mat::default();
+            match_color(&illumination_matched, &target_face_img, &mut color_corrected, auto_correction_strength)?;
 
-    // より自然なブレンディング（フェザリング強化）
-    blend_with_feathering(&color_corrected, &mut result, &mask, target_face.x, target_face.y)?;
+            // 質感補正: ぼけ具合とノイズをターゲット顔に合わせる
+            if swap_options.match_texture {
+                let mut texture_matched = core::Mat::default();
+                texture_adjustment = Some(match_texture(&color_corrected, &target_face_img, &mut texture_matched, &swap_options)?);
+                color_corrected = texture_matched;
+            }
+
+            // 楽円マスクを作成（顔全体を滑らかに合成）
+            let mut mask = create_ellipse_mask(target_face.width, target_face.height)?;
+            if let Some(keep) = &keep_mask {
+                mask = subtract_region(&mask, keep, target_face)?;
+            }
+
+            // ターゲットの顔（あごの輪郭+眉の凸包）のうち楕円で覆われない部分を修復
+            // 検出矩形の角は背景や髪なので対象にしない
+            if let Some(inpaint_options) = &swap_options.inpaint {
+                match facemark.landmarks(&target_img, target_face) {
+                    Ok(target_landmarks) => {
+                        let target_size = target_img.size().map_err(|e| e.to_string())?;
+                        let outline = landmark_region_mask(target_size, &target_landmarks, &[LANDMARK_JAW.start..LANDMARK_LEFT_EYEBROW.end], 0, 0.0)?;
+                        let face_rect_mask = core::Mat::new_size_with_default(target_face.size(), core::CV_8UC1, core::Scalar::all(255.0)).map_err(|e| e.to_string())?;
+                        let mut original_region = core::Mat::default();
+                        core::bitwise_and(&outline, &paste_mask(target_size, target_face, &face_rect_mask)?, &mut original_region, &core::Mat::default()).map_err(|e| e.to_string())?;
+                        let new_region = paste_mask(target_size, target_face, &mask)?;
+                        inpainted_pixels = inpaint_uncovered(&mut result, &original_region, &new_region, inpaint_options)?;
+                    }
+                    Err(e) => warnings.push(format!("顔の輪郭が取れないため修復をスキップしました: {}", e)),
+                }
+            }
+
+            // より自然なブレンディング（フェザリング強化）
+            blend_with_feathering(&color_corrected, &mut result, &mask, target_face.x, target_face.y)?;
+        }
+        SwapMode::Head => {
+            let (source_canvas, source_head, source_mask) = match &source_alpha {
+                Some(alpha) => (core::Rect::new(0, 0, source_img.cols(), source_img.rows()), source_img.clone(), alpha.clone()),
+                None => extract_face_with_mask(&source_img, &source_face, &swap_options.extract)?,
+            };
+            let source = HeadSource { canvas: source_canvas, head: &source_head, mask: &source_mask, face: source_face };
+            let target = HeadTarget { img: &target_img, face: target_face, face_img: &target_face_img };
+            (texture_adjustment, inpainted_pixels) = swap_head(
+                &source, &target, auto_correction_strength, &swap_options, keep_mask.as_ref(), &mut result,
+            )?;
+        }
+    }
 
     // エンコード
     let mut buf = core::Vector::<u8>::new();
@@ -334,140 +1570,1257 @@ fn face_swap(source_path: String, target_path: String, color_correction: Option<
     Ok(FaceSwapResult {
         base64: general_purpose::STANDARD.encode(buf.as_slice()),
         color_correction_strength: auto_correction_strength,
+        source_quality,
+        target_quality,
+        texture_adjustment,
+        inpainted_pixels,
+        source_pose,
+        target_pose,
+        mirrored,
+        used_source_alpha: source_alpha.is_some(),
+        warnings,
     })
 }
 
-fn extract_face_with_mask(img: &core::Mat, face: &core::Rect) -> Result<(core::Mat, core::Mat), String> {
-    let img_size = img.size().map_err(|e| e.to_string())?;
+// 部分入れ替えできる顔のパーツ
+#[derive(serde::Deserialize, serde::Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum FaceFeature {
+    Eyes,
+    Eyebrows,
+    Nose,
+    Mouth,
+}
 
-    // キャンバス確保
-    let canvas_margin_top = (face.height as f32 * 1.0) as i32;
-    let canvas_margin_bottom = (face.height as f32 * 0.2) as i32;
-    let canvas_margin_side = (face.width as f32 * 0.3) as i32;
+impl FaceFeature {
+    // 個別に位置合わせする単位（左右の目・眉はそれぞれ別に合わせる）
+    fn regions(self) -> Vec<std::ops::Range<usize>> {
+        match self {
+            FaceFeature::Eyes => vec![LANDMARK_RIGHT_EYE, LANDMARK_LEFT_EYE],
+            FaceFeature::Eyebrows => vec![LANDMARK_RIGHT_EYEBROW, LANDMARK_LEFT_EYEBROW],
+            FaceFeature::Nose => vec![LANDMARK_NOSE],
+            FaceFeature::Mouth => vec![LANDMARK_OUTER_MOUTH],
+        }
+    }
+}
 
-    let canvas_x = (face.x - canvas_margin_side).max(0);
-    let canvas_y = (face.y - canvas_margin_top).max(0);
-    let canvas_w = (face.width + canvas_margin_side * 2).min(img_size.width - canvas_x);
-    let canvas_h = (face.height + canvas_margin_top + canvas_margin_bottom).min(img_size.height - canvas_y);
+// partial swapの設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct PartialSwapOptions {
+    feature_dilate: i32,  // パーツのマスクを広げる幅（px）
+    feature_feather: f64, // パーツのマスクの縁のぼかし（σ）
+}
 
-    let canvas_rect = core::Rect::new(canvas_x, canvas_y, canvas_w, canvas_h);
-    let canvas_roi = core::Mat::roi(img, canvas_rect).map_err(|e| e.to_string())?;
-    let mut face_img = core::Mat::default();
-    canvas_roi.copy_to(&mut face_img).map_err(|e| e.to_string())?;
+impl Default for PartialSwapOptions {
+    fn default() -> Self {
+        PartialSwapOptions {
+            feature_dilate: 6,
+            feature_feather: 4.0,
+        }
+    }
+}
 
-    // ヒント枠
-    let hint_margin_x = (face.width as f32 * 0.15) as i32;
-    let hint_margin_top = (face.height as f32 * 0.05) as i32;
-    let hint_margin_bottom = (face.height as f32 * 0.15) as i32;  // 顧を含めるたも30%から15%に減少
-    
-    let hint_x = hint_margin_x;
-    let hint_y = hint_margin_top;
-    let hint_w = (face_img.cols() - hint_margin_x * 2).max(1);
-    let hint_h = (face_img.rows() - hint_margin_top - hint_margin_bottom).max(1);
-    let hint_rect = core::Rect::new(hint_x, hint_y, hint_w, hint_h);
+#[derive(serde::Serialize)]
+struct PartialSwapResult {
+    base64: String,                  // 合成結果画像
+    color_correction_strength: f64,  // 使用された色補正強度
+    features: Vec<FaceFeature>,      // 入れ替えたパーツ
+}
 
-    // マスク生成
-    let mask = create_high_quality_mask(&face_img, hint_rect)?;
+// 目・鼻・口・眉などのパーツだけをソースからターゲットに移す
+#[tauri::command]
+fn partial_swap(
+    source_path: String,
+    target_path: String,
+    features: Vec<FaceFeature>,
+    color_correction: Option<f64>,
+    detect_options: Option<DetectOptions>,
+    options: Option<PartialSwapOptions>,
+) -> Result<PartialSwapResult, String> {
+    opencv::core::set_use_optimized(true).ok();
+    opencv::core::set_num_threads(0).ok();
 
-    Ok((face_img, mask))
-}
+    if features.is_empty() {
+        return Err("入れ替えるパーツを選択してください".to_string());
+    }
+    let options = options.unwrap_or_default();
 
-// 楽円マスクを作成（face swap用）
-fn create_ellipse_mask(width: i32, height: i32) -> Result<core::Mat, String> {
-    let mut mask = core::Mat::new_size_with_default(
-        core::Size::new(width, height),
-        core::CV_8UC1,
-        core::Scalar::all(0.0)
-    ).map_err(|e| e.to_string())?;
+    let source_img = imgcodecs::imread(&source_path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "ソース画像の読み込みに失敗")?;
+    let target_img = imgcodecs::imread(&target_path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "ターゲット画像の読み込みに失敗")?;
 
-    let center = core::Point::new(width / 2, height / 2);
-    // 楕円を縦長にして髪への侵食を防ぐ（横幅を狭く、縦はそのまま）
-    let axes = core::Size::new((width as f32 * 0.40) as i32, (height as f32 * 0.48) as i32);
-    
-    // 白い楽円を描画
-    imgproc::ellipse(
-        &mut mask,
-        center,
-        axes,
-        0.0,
-        0.0,
-        360.0,
-        core::Scalar::all(255.0),
-        -1,
-        imgproc::LINE_8,
-        0
-    ).map_err(|e| e.to_string())?;
+    let detect_options = detect_options.unwrap_or_default();
+    let source_faces = detect_faces(&source_img, &detect_options)?.faces;
+    let target_faces = detect_faces(&target_img, &detect_options)?.faces;
+    let source_face = source_faces.first().ok_or("ソース画像に顔が検出されませんでした")?.rect;
+    let target_face = target_faces.first().ok_or("ターゲット画像に顔が検出されませんでした")?.rect;
 
-    // エッジを最小限にぼかす（境界を自然に）
-    let mut smooth_mask = core::Mat::default();
-    imgproc::gaussian_blur(
-        &mask,
-        &mut smooth_mask,
-        core::Size::new(5, 5),
-        1.5,
-        0.0,
-        core::BORDER_DEFAULT,
-        core::AlgorithmHint::ALGO_HINT_DEFAULT
-    ).map_err(|e| e.to_string())?;
+    let mut facemark = LazyFacemark::new();
+    let source_landmarks = facemark.landmarks(&source_img, source_face)?;
+    let target_landmarks = facemark.landmarks(&target_img, target_face)?;
 
-    Ok(smooth_mask)
-}
+    // 色補正強度は顔全体の肌色の差で決める（パーツ単位だと肌が少なすぎる）
+    let source_face_roi = core::Mat::roi(&source_img, source_face).map_err(|e| e.to_string())?;
+    let mut source_face_img = core::Mat::default();
+    source_face_roi.copy_to(&mut source_face_img).map_err(|e| e.to_string())?;
+    let target_face_roi = core::Mat::roi(&target_img, target_face).map_err(|e| e.to_string())?;
+    let mut target_face_img = core::Mat::default();
+    target_face_roi.copy_to(&mut target_face_img).map_err(|e| e.to_string())?;
 
-// 肌色の差に基づいて色補正強度を自動計算
-fn calculate_color_correction_strength(src: &core::Mat, target: &core::Mat) -> Result<f64, String> {
-    // 肌色を抽出（YCrCbカラースペース使用）
-    let mut src_ycrcb = core::Mat::default();
-    let mut target_ycrcb = core::Mat::default();
-    imgproc::cvt_color(src, &mut src_ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
-    imgproc::cvt_color(target, &mut target_ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    let correction_strength = match color_correction {
+        Some(strength) => strength,
+        None => calculate_color_correction_strength(&source_face_img, &target_face_img)?,
+    };
 
-    // 肌色範囲でマスクを作成clahe
-    let mut src_skin_mask = core::Mat::default();
-    let mut target_skin_mask = core::Mat::default();
-    let lower_skin = core::Scalar::new(0.0, 133.0, 77.0, 0.0);
-    let upper_skin = core::Scalar::new(255.0, 173.0, 127.0, 0.0);
-    
-    core::in_range(&src_ycrcb, &lower_skin, &upper_skin, &mut src_skin_mask).map_err(|e| e.to_string())?;
-    core::in_range(&target_ycrcb, &lower_skin, &upper_skin, &mut target_skin_mask).map_err(|e| e.to_string())?;
+    // パーツは眉やあごの近くまであるので顔矩形を少し広げた範囲で処理する
+    let target_size = target_img.size().map_err(|e| e.to_string())?;
+    let margin_x = target_face.width / 5;
+    let margin_y = target_face.height / 5;
+    let work_rect = clip_rect(
+        core::Rect::new(target_face.x - margin_x, target_face.y - margin_y, target_face.width + margin_x * 2, target_face.height + margin_y * 2),
+        target_size,
+    );
 
-    // 肌色領域の平均色を計算
-    let src_skin_mean = core::mean(src, &src_skin_mask).map_err(|e| e.to_string())?;
-    let target_skin_mean = core::mean(target, &target_skin_mask).map_err(|e| e.to_string())?;
+    let mut result = target_img.clone();
+    for feature in &features {
+        for region in feature.regions() {
+            // 1. このパーツの点同士で相似変換を求め、ソースをターゲットの座標系へ
+            let from = core::Vector::<core::Point2f>::from_iter(source_landmarks[region.clone()].iter().copied());
+            let to = core::Vector::<core::Point2f>::from_iter(target_landmarks[region.clone()].iter().copied());
+            let mut inliers = core::Mat::default();
+            let transform = calib3d::estimate_affine_partial_2d(&from, &to, &mut inliers, calib3d::LMEDS, 3.0, 2000, 0.99, 10)
+                .map_err(|e| e.to_string())?;
+            if transform.empty() {
+                return Err("パーツの位置合わせに失敗".to_string());
+            }
 
-    // 色の差を計算（ユークリッド距離）
-    let color_diff = (
-        (target_skin_mean[0] - src_skin_mean[0]).powi(2) +
-        (target_skin_mean[1] - src_skin_mean[1]).powi(2) +
-        (target_skin_mean[2] - src_skin_mean[2]).powi(2)
-    ).sqrt();
+            let mut warped = core::Mat::default();
+            imgproc::warp_affine(&source_img, &mut warped, &transform, target_size, imgproc::INTER_LINEAR, core::BORDER_REFLECT, core::Scalar::default())
+                .map_err(|e| e.to_string())?;
 
-    // 色差に基づいて補正強度を決定（差が大きいほど強く補正）
-    // 双曲線関数を使用: strength = max_strength * color_diff / (color_diff + k)
-    // これにより滑らかな曲線で強度が増加し、最終的に飽和する
-    let max_strength = 0.7;  // 最大強度70%
-    let k = 40.0;  // この値で強度カーブの傾きを調整（色差40で約半分の強度）
-    
-    let strength = max_strength * color_diff / (color_diff + k);
+            // 2. 既存の色補正でターゲットの肌色に合わせる
+            let warped_roi = core::Mat::roi(&warped, work_rect).map_err(|e| e.to_string())?;
+            let mut warped_work = core::Mat::default();
+            warped_roi.copy_to(&mut warped_work).map_err(|e| e.to_string())?;
+            let mut color_corrected = core::Mat::default();
+            match_color(&warped_work, &target_face_img, &mut color_corrected, correction_strength)?;
 
-    Ok(strength)
+            // 3. ターゲットのランドマークで作ったパーツ形状のマスクで合成
+            let region_mask = landmark_region_mask(target_size, &target_landmarks, &[region], options.feature_dilate, options.feature_feather)?;
+            let mask_roi = core::Mat::roi(&region_mask, work_rect).map_err(|e| e.to_string())?;
+            let mut mask = core::Mat::default();
+            mask_roi.copy_to(&mut mask).map_err(|e| e.to_string())?;
+
+            blend_with_feathering(&color_corrected, &mut result, &mask, work_rect.x, work_rect.y)?;
+        }
+    }
+
+    let mut buf = core::Vector::<u8>::new();
+    imgcodecs::imencode(".png", &result, &mut buf, &core::Vector::new())
+        .map_err(|e| e.to_string())?;
+
+    Ok(PartialSwapResult {
+        base64: general_purpose::STANDARD.encode(buf.as_slice()),
+        color_correction_strength: correction_strength,
+        features,
+    })
 }
 
-// 照明補正: ヒストグラムマッチングで明暗を合わせる
-fn match_illumination(src: &core::Mat, target: &core::Mat, dst: &mut core::Mat) -> Result<(), String> {
-    // LAB色空間に変換（L: 輝度、A/B: 色相）
-    let mut src_lab = core::Mat::default();
-    let mut target_lab = core::Mat::default();
-    imgproc::cvt_color(src, &mut src_lab, imgproc::COLOR_BGR2Lab, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
-    imgproc::cvt_color(target, &mut target_lab, imgproc::COLOR_BGR2Lab, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+// モーフィングの設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct MorphOptions {
+    face_only: bool,             // 顔の凸包だけをターゲットに合成（背景はターゲットのまま）
+    frames: usize,               // 連番出力するフレーム数（0なら連番なし）
+    frames_dir: Option<String>,  // 連番PNGの出力先フォルダ
+    video_path: Option<String>,  // MP4の出力先
+    fps: f64,
+}
 
-    // チャンネル分離
-    let mut src_channels = core::Vector::<core::Mat>::new();
-    let mut target_channels = core::Vector::<core::Mat>::new();
-    core::split(&src_lab, &mut src_channels).map_err(|e| e.to_string())?;
-    core::split(&target_lab, &mut target_channels).map_err(|e| e.to_string())?;
+impl Default for MorphOptions {
+    fn default() -> Self {
+        MorphOptions {
+            face_only: false,
+            frames: 0,
+            frames_dir: None,
+            video_path: None,
+            fps: 24.0,
+        }
+    }
+}
 
-    // L（輝度）チャンネルの統計を取得
-    let src_l = src_channels.get(0).map_err(|e| e.to_string())?;
+#[derive(serde::Serialize)]
+struct MorphResult {
+    base64: String,             // alphaで指定した1枚
+    frame_paths: Vec<String>,   // 書き出した連番PNG
+    video_path: Option<String>, // 書き出したMP4
+}
+
+// ソースとターゲットの顔を中間の形に変形してクロスディゾルブする
+// alpha: 0.0でターゲットそのまま、1.0でソースの顔（形と質感）
+#[tauri::command]
+fn face_morph(source_path: String, target_path: String, alpha: f64, detect_options: Option<DetectOptions>, options: Option<MorphOptions>) -> Result<MorphResult, String> {
+    opencv::core::set_use_optimized(true).ok();
+    opencv::core::set_num_threads(0).ok();
+
+    let options = options.unwrap_or_default();
+    let alpha = alpha.clamp(0.0, 1.0);
+
+    let source_img = imgcodecs::imread(&source_path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "ソース画像の読み込みに失敗")?;
+    let target_img = imgcodecs::imread(&target_path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "ターゲット画像の読み込みに失敗")?;
+
+    let detect_options = detect_options.unwrap_or_default();
+    let source_faces = detect_faces(&source_img, &detect_options)?.faces;
+    let target_faces = detect_faces(&target_img, &detect_options)?.faces;
+    let source_face = source_faces.first().ok_or("ソース画像に顔が検出されませんでした")?.rect;
+    let target_face = target_faces.first().ok_or("ターゲット画像に顔が検出されませんでした")?.rect;
+
+    let mut facemark = LazyFacemark::new();
+    let source_landmarks = facemark.landmarks(&source_img, source_face)?;
+    let target_landmarks = facemark.landmarks(&target_img, target_face)?;
+
+    // 1. ソースを相似変換でターゲットの座標系・画像サイズに揃える
+    let target_size = target_img.size().map_err(|e| e.to_string())?;
+    let (source_aligned, source_points) = align_to_landmarks(&source_img, &source_landmarks, &target_landmarks, target_size)?;
+
+    // 2. 画像の縁にも点を置いて全体を三角形分割（分割はターゲット側の点で一度だけ）
+    let mut source_points = source_points;
+    let mut target_points = target_landmarks.clone();
+    let border = border_points(target_size);
+    source_points.extend(border.iter().copied());
+    target_points.extend(border.iter().copied());
+    let triangles = delaunay_triangles(&target_points, target_size)?;
+
+    let render = |a: f64| -> Result<core::Mat, String> {
+        let frame = morph_frame(&source_aligned, &target_img, &source_points, &target_points, &triangles, a)?;
+        if !options.face_only {
+            return Ok(frame);
+        }
+        // 中間の形の顔の凸包だけをターゲットに合成
+        let mid: Vec<core::Point2f> = interpolate_points(&source_points, &target_points, a);
+        let mask = landmark_region_mask(target_size, &mid, &[0..68], 0, 4.0)?;
+        let mut composed = target_img.clone();
+        blend_with_feathering(&frame, &mut composed, &mask, 0, 0)?;
+        Ok(composed)
+    };
+
+    let result = render(alpha)?;
+    let mut buf = core::Vector::<u8>::new();
+    imgcodecs::imencode(".png", &result, &mut buf, &core::Vector::new())
+        .map_err(|e| e.to_string())?;
+
+    // 3. アニメーション用の連番・動画（0%→100%）
+    let mut frame_paths = Vec::new();
+    let mut video_path = None;
+    if options.frames > 1 && (options.frames_dir.is_some() || options.video_path.is_some()) {
+        if let Some(dir) = &options.frames_dir {
+            std::fs::create_dir_all(dir).map_err(|e| format!("出力フォルダを作成できません: {}", e))?;
+        }
+        let mut writer = match &options.video_path {
+            Some(path) => {
+                let fourcc = videoio::VideoWriter::fourcc('m', 'p', '4', 'v').map_err(|e| e.to_string())?;
+                let writer = videoio::VideoWriter::new(path, fourcc, options.fps, target_size, true).map_err(|e| e.to_string())?;
+                if !writer.is_opened().map_err(|e| e.to_string())? {
+                    return Err("動画ファイルを作成できません".to_string());
+                }
+                Some(writer)
+            }
+            None => None,
+        };
+
+        for i in 0..options.frames {
+            let a = i as f64 / (options.frames - 1) as f64;
+            let frame = render(a)?;
+            if let Some(dir) = &options.frames_dir {
+                let path = std::path::Path::new(dir).join(format!("morph_{:04}.png", i));
+                imgcodecs::imwrite(&path.to_string_lossy(), &frame, &core::Vector::new()).map_err(|e| e.to_string())?;
+                frame_paths.push(path.to_string_lossy().to_string());
+            }
+            if let Some(writer) = writer.as_mut() {
+                writer.write(&frame).map_err(|e| e.to_string())?;
+            }
+        }
+
+        if let Some(mut writer) = writer {
+            writer.release().map_err(|e| e.to_string())?;
+            video_path = options.video_path.clone();
+        }
+    }
+
+    Ok(MorphResult {
+        base64: general_purpose::STANDARD.encode(buf.as_slice()),
+        frame_paths,
+        video_path,
+    })
+}
+
+// ランドマーク同士の相似変換で画像をdst_landmarksの座標系に揃え、変換後の点も返す
+fn align_to_landmarks(img: &core::Mat, landmarks: &[core::Point2f], dst_landmarks: &[core::Point2f], dst_size: core::Size) -> Result<(core::Mat, Vec<core::Point2f>), String> {
+    let from = core::Vector::<core::Point2f>::from_iter(landmarks.iter().copied());
+    let to = core::Vector::<core::Point2f>::from_iter(dst_landmarks.iter().copied());
+    let mut inliers = core::Mat::default();
+    let transform = calib3d::estimate_affine_partial_2d(&from, &to, &mut inliers, calib3d::LMEDS, 3.0, 2000, 0.99, 10)
+        .map_err(|e| e.to_string())?;
+    if transform.empty() {
+        return Err("顔の位置合わせに失敗".to_string());
+    }
+
+    let mut aligned = core::Mat::default();
+    imgproc::warp_affine(img, &mut aligned, &transform, dst_size, imgproc::INTER_LINEAR, core::BORDER_REFLECT, core::Scalar::default())
+        .map_err(|e| e.to_string())?;
+
+    let mut moved = core::Vector::<core::Point2f>::new();
+    core::transform(&from, &mut moved, &transform).map_err(|e| e.to_string())?;
+
+    Ok((aligned, moved.to_vec()))
+}
+
+// 画像の四隅と各辺の中点
+fn border_points(size: core::Size) -> Vec<core::Point2f> {
+    let w = (size.width - 1) as f32;
+    let h = (size.height - 1) as f32;
+    vec![
+        core::Point2f::new(0.0, 0.0),
+        core::Point2f::new(w / 2.0, 0.0),
+        core::Point2f::new(w, 0.0),
+        core::Point2f::new(w, h / 2.0),
+        core::Point2f::new(w, h),
+        core::Point2f::new(w / 2.0, h),
+        core::Point2f::new(0.0, h),
+        core::Point2f::new(0.0, h / 2.0),
+    ]
+}
+
+// 点群をDelaunay分割し、各三角形を点の番号の組で返す
+fn delaunay_triangles(points: &[core::Point2f], size: core::Size) -> Result<Vec<[usize; 3]>, String> {
+    let mut subdiv = imgproc::Subdiv2D::new(core::Rect::new(0, 0, size.width, size.height)).map_err(|e| e.to_string())?;
+    for p in points {
+        let clamped = core::Point2f::new(
+            p.x.clamp(0.0, (size.width - 1) as f32),
+            p.y.clamp(0.0, (size.height - 1) as f32),
+        );
+        subdiv.insert(clamped).map_err(|e| e.to_string())?;
+    }
+
+    let mut triangle_list = core::Vector::<core::Vec6f>::new();
+    subdiv.get_triangle_list(&mut triangle_list).map_err(|e| e.to_string())?;
+
+    // 頂点座標から一番近い入力点の番号を引く
+    let nearest = |x: f32, y: f32| -> usize {
+        points.iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                let da = (a.x - x).powi(2) + (a.y - y).powi(2);
+                let db = (b.x - x).powi(2) + (b.y - y).powi(2);
+                da.total_cmp(&db)
+            })
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    };
+
+    let mut triangles = Vec::new();
+    for t in triangle_list.iter() {
+        let vertices = [(t[0], t[1]), (t[2], t[3]), (t[4], t[5])];
+        // 外側の仮想頂点を含む三角形は捨てる
+        if vertices.iter().any(|&(x, y)| x < 0.0 || y < 0.0 || x >= size.width as f32 || y >= size.height as f32) {
+            continue;
+        }
+        triangles.push([
+            nearest(vertices[0].0, vertices[0].1),
+            nearest(vertices[1].0, vertices[1].1),
+            nearest(vertices[2].0, vertices[2].1),
+        ]);
+    }
+
+    Ok(triangles)
+}
+
+// 2つの点群の線形補間（a=1.0でsrc、0.0でdst）
+fn interpolate_points(src: &[core::Point2f], dst: &[core::Point2f], a: f64) -> Vec<core::Point2f> {
+    let a = a as f32;
+    src.iter().zip(dst.iter())
+        .map(|(s, d)| core::Point2f::new(s.x * a + d.x * (1.0 - a), s.y * a + d.y * (1.0 - a)))
+        .collect()
+}
+
+// 両画像を中間の形に三角形ごとにワープしてブレンドした1フレーム
+fn morph_frame(
+    source: &core::Mat,
+    target: &core::Mat,
+    source_points: &[core::Point2f],
+    target_points: &[core::Point2f],
+    triangles: &[[usize; 3]],
+    a: f64,
+) -> Result<core::Mat, String> {
+    let mid = interpolate_points(source_points, target_points, a);
+
+    let mut warped_source = target.clone();
+    let mut warped_target = target.clone();
+    for t in triangles {
+        let tri_src = [source_points[t[0]], source_points[t[1]], source_points[t[2]]];
+        let tri_dst = [target_points[t[0]], target_points[t[1]], target_points[t[2]]];
+        let tri_mid = [mid[t[0]], mid[t[1]], mid[t[2]]];
+        warp_triangle(source, &mut warped_source, &tri_src, &tri_mid)?;
+        warp_triangle(target, &mut warped_target, &tri_dst, &tri_mid)?;
+    }
+
+    let mut frame = core::Mat::default();
+    core::add_weighted(&warped_source, a, &warped_target, 1.0 - a, 0.0, &mut frame, -1).map_err(|e| e.to_string())?;
+    Ok(frame)
+}
+
+// src内の三角形をdst内の三角形にアフィン変換して書き込む
+fn warp_triangle(src: &core::Mat, dst: &mut core::Mat, tri_src: &[core::Point2f; 3], tri_dst: &[core::Point2f; 3]) -> Result<(), String> {
+    let src_size = src.size().map_err(|e| e.to_string())?;
+    let dst_size = dst.size().map_err(|e| e.to_string())?;
+    let r_src = clip_rect(imgproc::bounding_rect(&core::Vector::<core::Point2f>::from_iter(tri_src.iter().copied())).map_err(|e| e.to_string())?, src_size);
+    let r_dst = clip_rect(imgproc::bounding_rect(&core::Vector::<core::Point2f>::from_iter(tri_dst.iter().copied())).map_err(|e| e.to_string())?, dst_size);
+    if r_src.width <= 0 || r_src.height <= 0 || r_dst.width <= 0 || r_dst.height <= 0 {
+        return Ok(());
+    }
+
+    // 各矩形の左上を原点にした座標
+    let local_src = core::Vector::<core::Point2f>::from_iter(tri_src.iter().map(|p| core::Point2f::new(p.x - r_src.x as f32, p.y - r_src.y as f32)));
+    let local_dst = core::Vector::<core::Point2f>::from_iter(tri_dst.iter().map(|p| core::Point2f::new(p.x - r_dst.x as f32, p.y - r_dst.y as f32)));
+
+    let transform = imgproc::get_affine_transform(&local_src, &local_dst).map_err(|e| e.to_string())?;
+    let src_patch = core::Mat::roi(src, r_src).map_err(|e| e.to_string())?;
+    let mut warped = core::Mat::default();
+    imgproc::warp_affine(&src_patch, &mut warped, &transform, r_dst.size(), imgproc::INTER_LINEAR, core::BORDER_REFLECT_101, core::Scalar::default())
+        .map_err(|e| e.to_string())?;
+
+    let mut mask = core::Mat::new_size_with_default(r_dst.size(), core::CV_8UC1, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+    let polygon = core::Vector::<core::Point>::from_iter(local_dst.iter().map(|p| core::Point::new(p.x.round() as i32, p.y.round() as i32)));
+    imgproc::fill_convex_poly(&mut mask, &polygon, core::Scalar::all(255.0), imgproc::LINE_8, 0).map_err(|e| e.to_string())?;
+
+    let mut dst_roi = core::Mat::roi_mut(dst, r_dst).map_err(|e| e.to_string())?;
+    warped.copy_to_masked(&mut dst_roi, &mask).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// 平均顔の設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct CompositeOptions {
+    size: i32,           // 出力画像の一辺（px）
+    alpha_feather: f64,  // 顔の輪郭のアルファのぼかし（σ）
+    detect_options: DetectOptions,
+}
+
+impl Default for CompositeOptions {
+    fn default() -> Self {
+        CompositeOptions {
+            size: 256,
+            alpha_feather: 6.0,
+            detect_options: DetectOptions::default(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct CompositeResult {
+    base64: String,              // 平均顔（アルファ付きPNG）
+    output_path: Option<String>, // 保存先
+    used: usize,                 // 平均に使った顔の数
+    skipped: Vec<String>,        // 顔やランドマークが取れず使えなかった画像
+}
+
+// 複数の画像から顔を1つずつ取り出し、標準の配置に揃えて平均した合成顔を作る
+// 出力はそのままface_swapのソースや匿名化の差し替え顔として使える
+#[tauri::command]
+fn composite_faces(paths: Vec<String>, output_path: Option<String>, options: Option<CompositeOptions>) -> Result<CompositeResult, String> {
+    opencv::core::set_use_optimized(true).ok();
+
+    let options = options.unwrap_or_default();
+    let size = core::Size::new(options.size, options.size);
+
+    // フォルダが渡されたら中の画像を全部使う
+    let mut files = Vec::new();
+    for path in &paths {
+        let p = std::path::Path::new(path);
+        if p.is_dir() {
+            files.extend(list_image_files(p)?);
+        } else {
+            files.push(p.to_path_buf());
+        }
+    }
+
+    // 1. 各画像の先頭の顔を、両目尻が標準位置に来るように相似変換で揃える
+    let eye_right = core::Point2f::new(0.3 * size.width as f32, size.height as f32 / 3.0);
+    let eye_left = core::Point2f::new(0.7 * size.width as f32, size.height as f32 / 3.0);
+    let mut facemark = LazyFacemark::new();
+    let mut aligned: Vec<(core::Mat, Vec<core::Point2f>)> = Vec::new();
+    let mut skipped = Vec::new();
+    for file in &files {
+        let name = file.to_string_lossy().to_string();
+        let img = match imgcodecs::imread(&name, imgcodecs::IMREAD_COLOR) {
+            Ok(img) if !img.empty() => img,
+            _ => {
+                skipped.push(name);
+                continue;
+            }
+        };
+        let face = match detect_faces(&img, &options.detect_options)?.faces.first() {
+            Some(face) => face.rect,
+            None => {
+                skipped.push(name);
+                continue;
+            }
+        };
+        let landmarks = match facemark.landmarks(&img, face) {
+            Ok(landmarks) => landmarks,
+            Err(_) => {
+                skipped.push(name);
+                continue;
+            }
+        };
+
+        let transform = similarity_from_two_points(landmarks[36], landmarks[45], eye_right, eye_left)?;
+        let mut warped = core::Mat::default();
+        imgproc::warp_affine(&img, &mut warped, &transform, size, imgproc::INTER_LINEAR, core::BORDER_REFLECT, core::Scalar::default())
+            .map_err(|e| e.to_string())?;
+        let mut moved = core::Vector::<core::Point2f>::new();
+        core::transform(&core::Vector::<core::Point2f>::from_iter(landmarks.iter().copied()), &mut moved, &transform)
+            .map_err(|e| e.to_string())?;
+
+        let mut points = moved.to_vec();
+        points.extend(border_points(size));
+        aligned.push((warped, points));
+    }
+
+    if aligned.is_empty() {
+        return Err("平均に使える顔がありませんでした".to_string());
+    }
+
+    // 2. 揃えたランドマークの平均を標準の顔形状とする
+    let n = aligned.len() as f32;
+    let mut mean_points = vec![core::Point2f::new(0.0, 0.0); aligned[0].1.len()];
+    for (_, points) in &aligned {
+        for (m, p) in mean_points.iter_mut().zip(points.iter()) {
+            m.x += p.x / n;
+            m.y += p.y / n;
+        }
+    }
+    let triangles = delaunay_triangles(&mean_points, size)?;
+
+    // 3. 各顔を標準形状に三角形ごとにワープして平均
+    let warped: Result<Vec<core::Mat>, String> = aligned.par_iter().map(|(img, points)| {
+        let mut out = img.clone();
+        for t in &triangles {
+            let tri_src = [points[t[0]], points[t[1]], points[t[2]]];
+            let tri_dst = [mean_points[t[0]], mean_points[t[1]], mean_points[t[2]]];
+            warp_triangle(img, &mut out, &tri_src, &tri_dst)?;
+        }
+        let mut out_f32 = core::Mat::default();
+        out.convert_to(&mut out_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
+        Ok(out_f32)
+    }).collect();
+
+    let mut sum = core::Mat::new_size_with_default(size, core::CV_32FC3, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+    for w in warped? {
+        let mut acc = core::Mat::default();
+        core::add(&sum, &w, &mut acc, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+        sum = acc;
+    }
+    let mut average = core::Mat::default();
+    sum.convert_to(&mut average, core::CV_8U, 1.0 / aligned.len() as f64, 0.0).map_err(|e| e.to_string())?;
+
+    // 4. 平均形状の輪郭をアルファにしてBGRAで出力（色は枠全体に残すのでアルファを無視しても使える）
+    let alpha = landmark_region_mask(size, &mean_points, &[0..68], 0, options.alpha_feather)?;
+    let bgra = merge_alpha(&average, &alpha)?;
+
+    let mut buf = core::Vector::<u8>::new();
+    imgcodecs::imencode(".png", &bgra, &mut buf, &core::Vector::new())
+        .map_err(|e| e.to_string())?;
+    if let Some(path) = &output_path {
+        std::fs::write(path, buf.as_slice()).map_err(|e| format!("平均顔を保存できません: {}", e))?;
+    }
+
+    Ok(CompositeResult {
+        base64: general_purpose::STANDARD.encode(buf.as_slice()),
+        output_path,
+        used: aligned.len(),
+        skipped,
+    })
+}
+
+// 2点の対応から相似変換（回転・等倍拡大・平行移動）の2x3行列を作る
+fn similarity_from_two_points(p1: core::Point2f, p2: core::Point2f, q1: core::Point2f, q2: core::Point2f) -> Result<core::Mat, String> {
+    let (dpx, dpy) = ((p2.x - p1.x) as f64, (p2.y - p1.y) as f64);
+    let (dqx, dqy) = ((q2.x - q1.x) as f64, (q2.y - q1.y) as f64);
+    let len_p = (dpx * dpx + dpy * dpy).sqrt();
+    if len_p < 1e-6 {
+        return Err("ランドマークが不正です".to_string());
+    }
+
+    let scale = (dqx * dqx + dqy * dqy).sqrt() / len_p;
+    let angle = dqy.atan2(dqx) - dpy.atan2(dpx);
+    let a = scale * angle.cos();
+    let b = scale * angle.sin();
+    let tx = q1.x as f64 - (a * p1.x as f64 - b * p1.y as f64);
+    let ty = q1.y as f64 - (b * p1.x as f64 + a * p1.y as f64);
+
+    core::Mat::from_slice_2d(&[
+        [a, -b, tx],
+        [b, a, ty],
+    ]).map_err(|e| e.to_string())
+}
+
+#[derive(serde::Serialize)]
+struct RemoveFaceResult {
+    base64: String,       // 顔を消した画像
+    removed: usize,       // 消した顔の数
+    inpainted_pixels: i32,
+}
+
+// 検出した顔を周囲の画素で塗りつぶして消す
+#[tauri::command]
+fn remove_face(path: String, detect_options: Option<DetectOptions>, inpaint_options: Option<InpaintOptions>) -> Result<RemoveFaceResult, String> {
+    let img = imgcodecs::imread(&path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "画像の読み込みに失敗")?;
+    let img_size = img.size().map_err(|e| e.to_string())?;
+
+    let faces = detect_faces(&img, &detect_options.unwrap_or_default())?.faces;
+    if faces.is_empty() {
+        return Err("顔が検出されませんでした".to_string());
+    }
+
+    // 全ての顔の楕円をまとめて1枚のマスクにする
+    let mut region = core::Mat::new_size_with_default(img_size, core::CV_8UC1, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+    for face in &faces {
+        let ellipse = paste_mask(img_size, face.rect, &create_ellipse_mask(face.rect.width, face.rect.height)?)?;
+        let mut merged = core::Mat::default();
+        core::bitwise_or(&region, &ellipse, &mut merged, &core::Mat::default()).map_err(|e| e.to_string())?;
+        region = merged;
+    }
+
+    let empty = core::Mat::new_size_with_default(img_size, core::CV_8UC1, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+    let mut result = img.clone();
+    let inpainted_pixels = inpaint_uncovered(&mut result, &region, &empty, &inpaint_options.unwrap_or_default())?;
+
+    let mut buf = core::Vector::<u8>::new();
+    imgcodecs::imencode(".png", &result, &mut buf, &core::Vector::new())
+        .map_err(|e| e.to_string())?;
+
+    Ok(RemoveFaceResult {
+        base64: general_purpose::STANDARD.encode(buf.as_slice()),
+        removed: faces.len(),
+        inpainted_pixels,
+    })
+}
+
+// 匿名化の設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct AnonymizeOptions {
+    style: AnonymizeStyle,
+    coverage: AnonymizeCoverage,
+    margin: f64,                     // 顔矩形を各辺この割合だけ広げる（rect/ellipse）
+    blur_strength: f64,              // ぼかしのσ（顔幅に対する割合）
+    pixel_blocks: i32,               // モザイクの横方向のブロック数
+    color: [u8; 3],                  // 塗りつぶし色（RGB）
+    replacement_path: Option<String>, // 差し替える顔画像（replaceでは必須。composite_facesの平均顔など別の画像の顔）
+    detect_options: DetectOptions,
+    extract: ExtractOptions,         // headの時の切り抜き範囲
+}
+
+// 匿名化の方法
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum AnonymizeStyle {
+    Blur,     // ガウスぼかし
+    Pixelate, // モザイク
+    Solid,    // 塗りつぶし
+    Replace,  // 平均顔・合成顔に差し替え
+}
+
+// 匿名化する範囲
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum AnonymizeCoverage {
+    Rect,    // 検出矩形
+    Ellipse, // 検出矩形に内接する楕円
+    Head,    // GrabCutの頭マスク（髪込み）
+}
+
+impl Default for AnonymizeOptions {
+    fn default() -> Self {
+        AnonymizeOptions {
+            style: AnonymizeStyle::Blur,
+            coverage: AnonymizeCoverage::Ellipse,
+            margin: 0.1,
+            blur_strength: 0.15,
+            pixel_blocks: 8,
+            color: [0, 0, 0],
+            replacement_path: None,
+            detect_options: DetectOptions::default(),
+            extract: ExtractOptions::for_swap(),
+        }
+    }
+}
+
+// 匿名化の結果（1ファイル分）
+#[derive(serde::Serialize)]
+struct AnonymizeResult {
+    input: String,
+    output: String,
+    faces: usize,          // 匿名化した顔の数
+    error: Option<String>, // このファイルだけ失敗した場合の理由
+    warning: Option<String>, // 顔が見つからず何も隠していない場合など
+}
+
+// 画像（またはフォルダ内の全画像）の顔を匿名化してディスクに書き出す
+// input_pathがフォルダならoutput_pathもフォルダとして扱い、同じファイル名で保存する
+#[tauri::command]
+fn anonymize_faces(input_path: String, output_path: String, options: Option<AnonymizeOptions>) -> Result<Vec<AnonymizeResult>, String> {
+    opencv::core::set_use_optimized(true).ok();
+
+    let options = options.unwrap_or_default();
+    let input = std::path::Path::new(&input_path);
+    let output = std::path::Path::new(&output_path);
+
+    // 差し替え用の顔はreplaceの時だけ一度読み込む
+    // 同じ画像の顔から作ると元の顔が残るので、replaceでは別画像の顔を必須にする
+    let replacement = if options.style == AnonymizeStyle::Replace {
+        let path = options.replacement_path.as_ref()
+            .ok_or("replaceには差し替える顔画像（replacement_path）を指定してください")?;
+        Some(load_replacement_face(path, &options.detect_options)?)
+    } else {
+        None
+    };
+
+    if !input.is_dir() {
+        let faces = anonymize_file(input, output, &options, replacement.as_ref())?;
+        return Ok(vec![AnonymizeResult {
+            input: input_path,
+            output: output_path,
+            faces,
+            error: None,
+            warning: no_faces_warning(faces),
+        }]);
+    }
+
+    std::fs::create_dir_all(output).map_err(|e| format!("出力フォルダを作成できません: {}", e))?;
+    let files = list_image_files(input)?;
+
+    let results = files.par_iter().map(|file| {
+        let out_file = output.join(file.file_name().unwrap_or_default());
+        let (faces, error) = match anonymize_file(file, &out_file, &options, replacement.as_ref()) {
+            Ok(faces) => (faces, None),
+            Err(e) => (0, Some(e)),
+        };
+        AnonymizeResult {
+            input: file.to_string_lossy().to_string(),
+            output: out_file.to_string_lossy().to_string(),
+            faces,
+            warning: if error.is_none() { no_faces_warning(faces) } else { None },
+            error,
+        }
+    }).collect();
+
+    Ok(results)
+}
+
+// 顔が1つも見つからなかったファイルは元のまま書き出されるので、見落とさないよう警告する
+fn no_faces_warning(faces: usize) -> Option<String> {
+    (faces == 0).then(|| "顔が検出されなかったため匿名化していません（元の画像のまま書き出しました）".to_string())
+}
+
+// フォルダ直下の画像ファイル（png/jpg/jpeg）をファイル名順で列挙
+fn list_image_files(dir: &std::path::Path) -> Result<Vec<std::path::PathBuf>, String> {
+    let mut files: Vec<std::path::PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("フォルダを読み込めません: {}", e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| matches!(ext.to_ascii_lowercase().as_str(), "png" | "jpg" | "jpeg"))
+                .unwrap_or(false)
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+// 1ファイルを匿名化して保存し、匿名化した顔の数を返す（顔が無ければそのまま保存）
+fn anonymize_file(input: &std::path::Path, output: &std::path::Path, options: &AnonymizeOptions, replacement: Option<&core::Mat>) -> Result<usize, String> {
+    let img = imgcodecs::imread(&input.to_string_lossy(), imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "画像の読み込みに失敗")?;
+    if img.empty() {
+        return Err("画像の読み込みに失敗".to_string());
+    }
+
+    let faces = detect_faces(&img, &options.detect_options)?.faces;
+    let mut result = img.clone();
+
+    for face in &faces {
+        anonymize_face(&img, &mut result, &face.rect, options, replacement)?;
+    }
+
+    let written = imgcodecs::imwrite(&output.to_string_lossy(), &result, &core::Vector::new())
+        .map_err(|e| e.to_string())?;
+    if !written {
+        return Err("画像の書き込みに失敗".to_string());
+    }
+
+    Ok(faces.len())
+}
+
+// 1つの顔を匿名化してresultに合成
+fn anonymize_face(img: &core::Mat, result: &mut core::Mat, face: &core::Rect, options: &AnonymizeOptions, replacement: Option<&core::Mat>) -> Result<(), String> {
+    let img_size = img.size().map_err(|e| e.to_string())?;
+    // 負の余白だと検出矩形より狭くなり、顔の端が残る（replaceの貼り付けも失敗する）
+    let margin = options.margin.max(0.0);
+    let mx = (face.width as f64 * margin) as i32;
+    let my = (face.height as f64 * margin) as i32;
+
+    // 1. 範囲（ROI矩形とその中のマスク）を決める
+    let (roi_rect, mask) = match options.coverage {
+        AnonymizeCoverage::Head => {
+            let (canvas, _, mut head_mask) = extract_face_with_mask(img, face, &options.extract)?;
+            // GrabCutが失敗しても顔は必ず隠れるよう、検出矩形の楕円を足す
+            imgproc::ellipse(
+                &mut head_mask,
+                core::Point::new(face.x - canvas.x + face.width / 2, face.y - canvas.y + face.height / 2),
+                core::Size::new(face.width / 2 + mx, face.height / 2 + my),
+                0.0, 0.0, 360.0,
+                core::Scalar::all(255.0),
+                -1, imgproc::LINE_8, 0
+            ).map_err(|e| e.to_string())?;
+            (canvas, head_mask)
+        }
+        coverage => {
+            let rect = clip_rect(core::Rect::new(face.x - mx, face.y - my, face.width + mx * 2, face.height + my * 2), img_size);
+            let fill = if coverage == AnonymizeCoverage::Rect { 255.0 } else { 0.0 };
+            let mut mask = core::Mat::new_size_with_default(rect.size(), core::CV_8UC1, core::Scalar::all(fill)).map_err(|e| e.to_string())?;
+            if coverage == AnonymizeCoverage::Ellipse {
+                imgproc::ellipse(
+                    &mut mask,
+                    core::Point::new(rect.width / 2, rect.height / 2),
+                    core::Size::new(rect.width / 2, rect.height / 2),
+                    0.0, 0.0, 360.0,
+                    core::Scalar::all(255.0),
+                    -1, imgproc::LINE_8, 0
+                ).map_err(|e| e.to_string())?;
+            }
+            (rect, mask)
+        }
+    };
+
+    let roi = core::Mat::roi(img, roi_rect).map_err(|e| e.to_string())?;
+    let mut region = core::Mat::default();
+    roi.copy_to(&mut region).map_err(|e| e.to_string())?;
+
+    // 2. 方法ごとに置き換え画像を作る
+    let mut anonymized = core::Mat::default();
+    match options.style {
+        AnonymizeStyle::Blur => {
+            let sigma = (face.width as f64 * options.blur_strength).max(1.0);
+            imgproc::gaussian_blur(&region, &mut anonymized, core::Size::new(0, 0), sigma, 0.0, core::BORDER_REPLICATE, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+        }
+        AnonymizeStyle::Pixelate => {
+            // 縮小してから最近傍で拡大するとブロック状になる
+            let blocks_x = options.pixel_blocks.max(1);
+            let blocks_y = ((blocks_x as f64 * region.rows() as f64 / region.cols() as f64).round() as i32).max(1);
+            let mut small = core::Mat::default();
+            imgproc::resize(&region, &mut small, core::Size::new(blocks_x, blocks_y), 0.0, 0.0, imgproc::INTER_AREA).map_err(|e| e.to_string())?;
+            imgproc::resize(&small, &mut anonymized, region.size().map_err(|e| e.to_string())?, 0.0, 0.0, imgproc::INTER_NEAREST).map_err(|e| e.to_string())?;
+        }
+        AnonymizeStyle::Solid => {
+            let [r, g, b] = options.color;
+            anonymized = core::Mat::new_size_with_default(
+                region.size().map_err(|e| e.to_string())?,
+                core::CV_8UC3,
+                core::Scalar::new(b as f64, g as f64, r as f64, 0.0), // BGRの順
+            ).map_err(|e| e.to_string())?;
+        }
+        AnonymizeStyle::Replace => {
+            let replacement = replacement.ok_or("差し替える顔がありません")?;
+            // 差し替え顔は検出矩形に合わせ、残りは元画像の肌色に合わせる
+            let mut resized = core::Mat::default();
+            imgproc::resize(replacement, &mut resized, face.size(), 0.0, 0.0, imgproc::INTER_LANCZOS4).map_err(|e| e.to_string())?;
+            let face_roi = core::Mat::roi(img, *face).map_err(|e| e.to_string())?;
+            let mut face_img = core::Mat::default();
+            face_roi.copy_to(&mut face_img).map_err(|e| e.to_string())?;
+            let mut corrected = core::Mat::default();
+            match_color(&resized, &face_img, &mut corrected, 0.7)?;
+
+            // ROIは必ず検出矩形を含むので、その位置に貼るだけでよい
+            anonymized = region.clone();
+            let local = core::Rect::new(face.x - roi_rect.x, face.y - roi_rect.y, face.width, face.height);
+            let mut dst_roi = core::Mat::roi_mut(&mut anonymized, local).map_err(|e| e.to_string())?;
+            corrected.copy_to(&mut dst_roi).map_err(|e| e.to_string())?;
+        }
+    }
+
+    // 3. マスクで合成
+    blend_with_feathering(&anonymized, result, &mask, roi_rect.x, roi_rect.y)
+}
+
+// 差し替え用の顔画像を読み込む（顔が検出できればその矩形だけ、できなければ画像全体）
+fn load_replacement_face(path: &str, detect_options: &DetectOptions) -> Result<core::Mat, String> {
+    let img = imgcodecs::imread(path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "差し替え顔の読み込みに失敗")?;
+    if img.empty() {
+        return Err("差し替え顔の読み込みに失敗".to_string());
+    }
+
+    let faces = detect_faces(&img, detect_options)?.faces;
+    match faces.first() {
+        Some(face) => {
+            let roi = core::Mat::roi(&img, face.rect).map_err(|e| e.to_string())?;
+            let mut face_img = core::Mat::default();
+            roi.copy_to(&mut face_img).map_err(|e| e.to_string())?;
+            Ok(face_img)
+        }
+        None => Ok(img),
+    }
+}
+
+// 画像を読み込み、4チャンネルならBGRとアルファに分けて返す
+fn read_with_alpha(path: &str) -> Result<(core::Mat, Option<core::Mat>), String> {
+    let raw = imgcodecs::imread(path, imgcodecs::IMREAD_UNCHANGED)
+        .map_err(|_| "ソース画像の読み込みに失敗")?;
+    if raw.empty() {
+        return Err("ソース画像の読み込みに失敗".to_string());
+    }
+
+    if raw.channels() == 4 && raw.depth() == core::CV_8U {
+        let mut bgr = core::Mat::default();
+        let mut alpha = core::Mat::default();
+        imgproc::cvt_color(&raw, &mut bgr, imgproc::COLOR_BGRA2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+        core::extract_channel(&raw, &mut alpha, 3).map_err(|e| e.to_string())?;
+        return Ok((bgr, Some(alpha)));
+    }
+
+    // グレースケールや16bitなどはいつも通りBGRで読み直す
+    let img = imgcodecs::imread(path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "ソース画像の読み込みに失敗")?;
+    Ok((img, None))
+}
+
+// process_faceのキャンバス（余白はoptionsのもの、画像の端で切り詰められていない前提）から顔矩形を逆算
+fn face_rect_in_canvas(canvas: core::Size, options: &ExtractOptions) -> core::Rect {
+    let options = options.resolved();
+    let face_w = (canvas.width as f64 / (1.0 + options.canvas_side * 2.0)) as i32;
+    let face_h = (canvas.height as f64 / (1.0 + options.canvas_top + options.canvas_bottom)) as i32;
+    core::Rect::new((face_w as f64 * options.canvas_side) as i32, (face_h as f64 * options.canvas_top) as i32, face_w, face_h)
+}
+
+// swap_headに渡すソースの頭（canvasはソース画像内の切り抜き位置、faceはソース画像での顔矩形）
+struct HeadSource<'a> {
+    canvas: core::Rect,
+    head: &'a core::Mat,
+    mask: &'a core::Mat,
+    face: core::Rect,
+}
+
+// swap_headの合成先（face_imgは色補正の基準にするターゲット顔の切り抜き）
+struct HeadTarget<'a> {
+    img: &'a core::Mat,
+    face: core::Rect,
+    face_img: &'a core::Mat,
+}
+
+// 頭全体の入れ替え: 切り抜いたソースの頭（髪込み）をターゲットの頭に合わせて配置し、
+// 新しい頭で隠れないターゲットの髪を修復してからマスクのアルファで合成する
+fn swap_head(
+    source: &HeadSource,
+    target: &HeadTarget,
+    correction_strength: f64,
+    options: &SwapOptions,
+    keep_mask: Option<&core::Mat>,
+    result: &mut core::Mat,
+) -> Result<(Option<TextureAdjustment>, i32), String> {
+    let HeadSource { canvas: source_canvas, head: source_head, mask: source_mask, face: source_face } = *source;
+    let HeadTarget { img: target_img, face: target_face, face_img: target_face_img } = *target;
+    let target_size = target_img.size().map_err(|e| e.to_string())?;
+
+    // 1. 顔の幅が揃うように拡大縮小し、顔の位置が重なるように配置
+    let scale = target_face.width as f64 / source_face.width as f64;
+    let scaled_size = core::Size::new(
+        ((source_canvas.width as f64 * scale) as i32).max(1),
+        ((source_canvas.height as f64 * scale) as i32).max(1),
+    );
+    let mut scaled_head = core::Mat::default();
+    let mut scaled_mask = core::Mat::default();
+    imgproc::resize(source_head, &mut scaled_head, scaled_size, 0.0, 0.0, imgproc::INTER_LANCZOS4).map_err(|e| e.to_string())?;
+    imgproc::resize(source_mask, &mut scaled_mask, scaled_size, 0.0, 0.0, imgproc::INTER_LINEAR).map_err(|e| e.to_string())?;
+
+    let placed = core::Rect::new(
+        target_face.x - ((source_face.x - source_canvas.x) as f64 * scale) as i32,
+        target_face.y - ((source_face.y - source_canvas.y) as f64 * scale) as i32,
+        scaled_size.width,
+        scaled_size.height,
+    );
+
+    // 2. 色と質感をターゲットに合わせる（髪や背景が統計に混ざるので肌色ベースの色補正のみ）
+    let mut color_corrected = core::Mat::default();
+    match_color(&scaled_head, target_face_img, &mut color_corrected, correction_strength)?;
+
+    let mut texture_adjustment = None;
+    if options.match_texture {
+        let mut texture_matched = core::Mat::default();
+        texture_adjustment = Some(match_texture(&color_corrected, target_face_img, &mut texture_matched, options)?);
+        color_corrected = texture_matched;
+    }
+
+    // 3. 画像からはみ出す部分を切り落とす
+    let clipped = clip_rect(placed, target_size);
+    if clipped.width <= 0 || clipped.height <= 0 {
+        return Err("頭の配置先が画像の外です".to_string());
+    }
+    let local = core::Rect::new(clipped.x - placed.x, clipped.y - placed.y, clipped.width, clipped.height);
+    let mut head_clipped = core::Mat::default();
+    let mut mask_clipped = core::Mat::default();
+    core::Mat::roi(&color_corrected, local).map_err(|e| e.to_string())?.copy_to(&mut head_clipped).map_err(|e| e.to_string())?;
+    core::Mat::roi(&scaled_mask, local).map_err(|e| e.to_string())?.copy_to(&mut mask_clipped).map_err(|e| e.to_string())?;
+
+    // 4. ターゲットの元の頭のうち、新しい頭で覆われない部分（はみ出した髪など）を修復
+    let (target_canvas, _, target_mask) = extract_face_with_mask(target_img, &target_face, &options.extract)?;
+    let old_head = paste_mask(target_size, target_canvas, &target_mask)?;
+    let new_head = paste_mask(target_size, clipped, &mask_clipped)?;
+    let inpaint_options = options.inpaint.clone().unwrap_or_default();
+    let inpainted_pixels = inpaint_uncovered(result, &old_head, &new_head, &inpaint_options)?;
+
+    // 5. GrabCutのアルファで合成（残す部位はマスクから引く）
+    if let Some(keep) = keep_mask {
+        mask_clipped = subtract_region(&mask_clipped, keep, clipped)?;
+    }
+    blend_with_feathering(&head_clipped, result, &mask_clipped, clipped.x, clipped.y)?;
+
+    Ok((texture_adjustment, inpainted_pixels))
+}
+
+// 元の領域のうち新しいマスクで覆われない部分を修復し、修復した画素数を返す
+// original_region / new_region はどちらも画像全体サイズの8bitマスク（new_regionはソフトでも可）
+fn inpaint_uncovered(img: &mut core::Mat, original_region: &core::Mat, new_region: &core::Mat, options: &InpaintOptions) -> Result<i32, String> {
+    let mut new_bin = core::Mat::default();
+    imgproc::threshold(new_region, &mut new_bin, 127.0, 255.0, imgproc::THRESH_BINARY).map_err(|e| e.to_string())?;
+    let mut not_new = core::Mat::default();
+    core::bitwise_not(&new_bin, &mut not_new, &core::Mat::default()).map_err(|e| e.to_string())?;
+    let mut uncovered = core::Mat::default();
+    core::bitwise_and(original_region, &not_new, &mut uncovered, &core::Mat::default()).map_err(|e| e.to_string())?;
+
+    // 新しいマスクの縁から一定幅の帯だけに限定（新しいマスクが空なら全体を修復）
+    if options.band > 0 && core::count_non_zero(&new_bin).map_err(|e| e.to_string())? > 0 {
+        let band_kernel = imgproc::get_structuring_element(
+            imgproc::MORPH_ELLIPSE,
+            core::Size::new(options.band * 2 + 1, options.band * 2 + 1),
+            core::Point::new(-1, -1),
+        ).map_err(|e| e.to_string())?;
+        let mut band_area = core::Mat::default();
+        imgproc::dilate(&new_bin, &mut band_area, &band_kernel, core::Point::new(-1, -1), 1, core::BORDER_CONSTANT, core::Scalar::default()).map_err(|e| e.to_string())?;
+        let mut limited = core::Mat::default();
+        core::bitwise_and(&uncovered, &band_area, &mut limited, &core::Mat::default()).map_err(|e| e.to_string())?;
+        uncovered = limited;
+    }
+
+    // 輪郭のにじみも消えるよう少し膨らませる
+    if options.dilate > 0 {
+        let kernel = imgproc::get_structuring_element(
+            imgproc::MORPH_ELLIPSE,
+            core::Size::new(options.dilate * 2 + 1, options.dilate * 2 + 1),
+            core::Point::new(-1, -1),
+        ).map_err(|e| e.to_string())?;
+        let mut dilated = core::Mat::default();
+        imgproc::dilate(&uncovered, &mut dilated, &kernel, core::Point::new(-1, -1), 1, core::BORDER_CONSTANT, core::Scalar::default()).map_err(|e| e.to_string())?;
+        uncovered = dilated;
+    }
+
+    let filled = core::count_non_zero(&uncovered).map_err(|e| e.to_string())?;
+    if filled == 0 {
+        return Ok(0);
+    }
+
+    let mut inpainted = core::Mat::default();
+    match options.method {
+        InpaintMethod::Telea => {
+            photo::inpaint(&*img, &uncovered, &mut inpainted, options.radius, photo::INPAINT_TELEA).map_err(|e| e.to_string())?;
+        }
+        InpaintMethod::Ns => {
+            photo::inpaint(&*img, &uncovered, &mut inpainted, options.radius, photo::INPAINT_NS).map_err(|e| e.to_string())?;
+        }
+        InpaintMethod::Exemplar => {
+            // xphotoのマスクは「有効な画素」が非ゼロなので反転して渡す
+            let mut valid = core::Mat::default();
+            core::bitwise_not(&uncovered, &mut valid, &core::Mat::default()).map_err(|e| e.to_string())?;
+            xphoto::inpaint(&*img, &valid, &mut inpainted, xphoto::INPAINT_SHIFTMAP).map_err(|e| e.to_string())?;
+        }
+    }
+    *img = inpainted;
+
+    Ok(filled)
+}
+
+// ローカルマスクから、画像全体サイズのマスクのうちrectの範囲を引く
+fn subtract_region(mask: &core::Mat, full_region: &core::Mat, rect: core::Rect) -> Result<core::Mat, String> {
+    let region_roi = core::Mat::roi(full_region, rect).map_err(|e| e.to_string())?;
+    let mut result = core::Mat::default();
+    core::subtract(mask, &region_roi, &mut result, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    Ok(result)
+}
+
+// 矩形を画像サイズの範囲に収める
+fn clip_rect(rect: core::Rect, size: core::Size) -> core::Rect {
+    let x1 = rect.x.max(0);
+    let y1 = rect.y.max(0);
+    let x2 = (rect.x + rect.width).min(size.width);
+    let y2 = (rect.y + rect.height).min(size.height);
+    core::Rect::new(x1, y1, (x2 - x1).max(0), (y2 - y1).max(0))
+}
+
+// 部分マスクを画像全体サイズのマスクに貼り付ける
+fn paste_mask(size: core::Size, rect: core::Rect, mask: &core::Mat) -> Result<core::Mat, String> {
+    let mut full = core::Mat::new_size_with_default(size, core::CV_8UC1, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+    let mut roi = core::Mat::roi_mut(&mut full, rect).map_err(|e| e.to_string())?;
+    mask.copy_to(&mut roi).map_err(|e| e.to_string())?;
+    Ok(full)
+}
+
+fn extract_face_with_mask(img: &core::Mat, face: &core::Rect, options: &ExtractOptions) -> Result<(core::Rect, core::Mat, core::Mat), String> {
+    let img_size = img.size().map_err(|e| e.to_string())?;
+    let geometry = extract_geometry(face, img_size, options);
+
+    let canvas_roi = core::Mat::roi(img, geometry.canvas).map_err(|e| e.to_string())?;
+    let mut face_img = core::Mat::default();
+    canvas_roi.copy_to(&mut face_img).map_err(|e| e.to_string())?;
+
+    // マスク生成
+    let (_, mask, _) = segment_canvas(&face_img, geometry.hint, options)?;
+
+    Ok((geometry.canvas, face_img, mask))
+}
+
+// 顔矩形からキャンバスとGrabCutのヒント枠を決める（process_faceとface swapで共通）
+fn extract_geometry(face: &core::Rect, img_size: core::Size, options: &ExtractOptions) -> ExtractGeometry {
+    let options = options.resolved();
+
+    // キャンバス確保 (画像の端で切り詰める)
+    let canvas_margin_top = (face.height as f64 * options.canvas_top) as i32;
+    let canvas_margin_bottom = (face.height as f64 * options.canvas_bottom) as i32;
+    let canvas_margin_side = (face.width as f64 * options.canvas_side) as i32;
+
+    let canvas_x = (face.x - canvas_margin_side).max(0);
+    let canvas_y = (face.y - canvas_margin_top).max(0);
+    let canvas_w = (face.width + canvas_margin_side * 2).min(img_size.width - canvas_x);
+    let canvas_h = (face.height + canvas_margin_top + canvas_margin_bottom).min(img_size.height - canvas_y);
+
+    // ヒント枠 (キャンバスの縁から除外)
+    let hint_margin_x = (face.width as f64 * options.hint_side) as i32;
+    let hint_margin_top = (face.height as f64 * options.hint_top) as i32;
+    let hint_margin_bottom = (face.height as f64 * options.hint_bottom) as i32;
+
+    let hint_w = (canvas_w - hint_margin_x * 2).max(1);
+    let hint_h = (canvas_h - hint_margin_top - hint_margin_bottom).max(1);
+
+    ExtractGeometry {
+        canvas: core::Rect::new(canvas_x, canvas_y, canvas_w, canvas_h),
+        hint: core::Rect::new(hint_margin_x, hint_margin_top, hint_w, hint_h),
+    }
+}
+
+// 楽円マスクを作成（face swap用）
+fn create_ellipse_mask(width: i32, height: i32) -> Result<core::Mat, String> {
+    let mut mask = core::Mat::new_size_with_default(
+        core::Size::new(width, height),
+        core::CV_8UC1,
+        core::Scalar::all(0.0)
+    ).map_err(|e| e.to_string())?;
+
+    let center = core::Point::new(width / 2, height / 2);
+    // 楕円を縦長にして髪への侵食を防ぐ（横幅を狭く、縦はそのまま）
+    let axes = core::Size::new((width as f32 * 0.40) as i32, (height as f32 * 0.48) as i32);
+    
+    // 白い楽円を描画
+    imgproc::ellipse(
+        &mut mask,
+        center,
+        axes,
+        0.0,
+        0.0,
+        360.0,
+        core::Scalar::all(255.0),
+        -1,
+        imgproc::LINE_8,
+        0
+    ).map_err(|e| e.to_string())?;
+
+    // エッジを最小限にぼかす（境界を自然に）
+    let mut smooth_mask = core::Mat::default();
+    imgproc::gaussian_blur(
+        &mask,
+        &mut smooth_mask,
+        core::Size::new(5, 5),
+        1.5,
+        0.0,
+        core::BORDER_DEFAULT,
+        core::AlgorithmHint::ALGO_HINT_DEFAULT
+    ).map_err(|e| e.to_string())?;
+
+    Ok(smooth_mask)
+}
+
+// 肌色の差に基づいて色補正強度を自動計算
+fn calculate_color_correction_strength(src: &core::Mat, target: &core::Mat) -> Result<f64, String> {
+    // 肌色を抽出（YCrCbカラースペース使用）
+    let mut src_ycrcb = core::Mat::default();
+    let mut target_ycrcb = core::Mat::default();
+    imgproc::cvt_color(src, &mut src_ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    imgproc::cvt_color(target, &mut target_ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    // 肌色範囲でマスクを作成clahe
+    let mut src_skin_mask = core::Mat::default();
+    let mut target_skin_mask = core::Mat::default();
+    let lower_skin = core::Scalar::new(0.0, 133.0, 77.0, 0.0);
+    let upper_skin = core::Scalar::new(255.0, 173.0, 127.0, 0.0);
+    
+    core::in_range(&src_ycrcb, &lower_skin, &upper_skin, &mut src_skin_mask).map_err(|e| e.to_string())?;
+    core::in_range(&target_ycrcb, &lower_skin, &upper_skin, &mut target_skin_mask).map_err(|e| e.to_string())?;
+
+    // 肌色領域の平均色を計算
+    let src_skin_mean = core::mean(src, &src_skin_mask).map_err(|e| e.to_string())?;
+    let target_skin_mean = core::mean(target, &target_skin_mask).map_err(|e| e.to_string())?;
+
+    // 色の差を計算（ユークリッド距離）
+    let color_diff = (
+        (target_skin_mean[0] - src_skin_mean[0]).powi(2) +
+        (target_skin_mean[1] - src_skin_mean[1]).powi(2) +
+        (target_skin_mean[2] - src_skin_mean[2]).powi(2)
+    ).sqrt();
+
+    // 色差に基づいて補正強度を決定（差が大きいほど強く補正）
+    // 双曲線関数を使用: strength = max_strength * color_diff / (color_diff + k)
+    // これにより滑らかな曲線で強度が増加し、最終的に飽和する
+    let max_strength = 0.7;  // 最大強度70%
+    let k = 40.0;  // この値で強度カーブの傾きを調整（色差40で約半分の強度）
+    
+    let strength = max_strength * color_diff / (color_diff + k);
+
+    Ok(strength)
+}
+
+// 照明補正: ヒストグラムマッチングで明暗を合わせる
+fn match_illumination(src: &core::Mat, target: &core::Mat, dst: &mut core::Mat) -> Result<(), String> {
+    // LAB色空間に変換（L: 輝度、A/B: 色相）
+    let mut src_lab = core::Mat::default();
+    let mut target_lab = core::Mat::default();
+    imgproc::cvt_color(src, &mut src_lab, imgproc::COLOR_BGR2Lab, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    imgproc::cvt_color(target, &mut target_lab, imgproc::COLOR_BGR2Lab, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    // チャンネル分離
+    let mut src_channels = core::Vector::<core::Mat>::new();
+    let mut target_channels = core::Vector::<core::Mat>::new();
+    core::split(&src_lab, &mut src_channels).map_err(|e| e.to_string())?;
+    core::split(&target_lab, &mut target_channels).map_err(|e| e.to_string())?;
+
+    // L（輝度）チャンネルの統計を取得
+    let src_l = src_channels.get(0).map_err(|e| e.to_string())?;
     let target_l = target_channels.get(0).map_err(|e| e.to_string())?;
     
     let mut src_mean = core::Scalar::default();
@@ -492,147 +2845,1648 @@ fn match_illumination(src: &core::Mat, target: &core::Mat, dst: &mut core::Mat)
     let mut scaled = core::Mat::default();
     core::subtract(&normalized_l, &core::Scalar::all(src_mean[0]), &mut scaled, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
     
-    let mut fully_matched_l = core::Mat::default();
-    scaled.convert_to(&mut fully_matched_l, core::CV_8U, scale, target_mean[0]).map_err(|e| e.to_string())?;
+    let mut fully_matched_l = core::Mat::default();
+    scaled.convert_to(&mut fully_matched_l, core::CV_8U, scale, target_mean[0]).map_err(|e| e.to_string())?;
+    
+    // 元の輝度とマッチング後の輝度をブレンド（30%のみ適用）
+    let mut matched_l = core::Mat::default();
+    core::add_weighted(&src_l, 0.7, &fully_matched_l, 0.3, 0.0, &mut matched_l, -1).map_err(|e| e.to_string())?;
+    
+    // マッチングしたLチャンネルをセット
+    src_channels.set(0, matched_l).map_err(|e| e.to_string())?;
+    
+    // チャンネル結合
+    let mut matched_lab = core::Mat::default();
+    core::merge(&src_channels, &mut matched_lab).map_err(|e| e.to_string())?;
+    
+    // BGRに戻す
+    imgproc::cvt_color(&matched_lab, dst, imgproc::COLOR_Lab2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// 質感補正: ソースのシャープさとノイズ量をターゲットに合わせる
+// くっきりしたソースはぼかし+粒状ノイズ、ぼやけたソースは軽くシャープ化
+fn match_texture(src: &core::Mat, target: &core::Mat, dst: &mut core::Mat, options: &SwapOptions) -> Result<TextureAdjustment, String> {
+    let to_gray = |img: &core::Mat| -> Result<core::Mat, String> {
+        let mut gray = core::Mat::default();
+        imgproc::cvt_color(img, &mut gray, imgproc::COLOR_BGR2GRAY, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+        Ok(gray)
+    };
+    let src_gray = to_gray(src)?;
+    let target_gray = to_gray(target)?;
+
+    let source_sharpness = laplacian_variance(&src_gray)?;
+    let target_sharpness = laplacian_variance(&target_gray)?;
+    let source_noise = estimate_noise(&src_gray)?;
+    let target_noise = estimate_noise(&target_gray)?;
+
+    // 1. ぼかし: ソースのシャープさがターゲットを下回るσを探す（最大3.0）
+    let blur_sigma = match options.blur_sigma {
+        Some(sigma) => sigma,
+        None if source_sharpness > target_sharpness * 1.2 => {
+            let mut found = 3.0;
+            let mut sigma = 0.3;
+            while sigma < 3.0 {
+                let mut blurred = core::Mat::default();
+                imgproc::gaussian_blur(&src_gray, &mut blurred, core::Size::new(0, 0), sigma, 0.0, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+                if laplacian_variance(&blurred)? <= target_sharpness {
+                    found = sigma;
+                    break;
+                }
+                sigma += 0.1;
+            }
+            found
+        }
+        None => 0.0,
+    };
+
+    // 2. シャープ化: ソースの方がぼやけている時だけ控えめに（最大0.5）
+    let sharpen_amount = match options.sharpen_amount {
+        Some(amount) => amount,
+        None if blur_sigma <= 0.0 && source_sharpness > 0.0 && source_sharpness < target_sharpness * 0.8 => {
+            ((target_sharpness / source_sharpness).sqrt() - 1.0).clamp(0.0, 0.5)
+        }
+        None => 0.0,
+    };
+
+    let mut adjusted = src.clone();
+    if blur_sigma > 0.0 {
+        let mut blurred = core::Mat::default();
+        imgproc::gaussian_blur(&adjusted, &mut blurred, core::Size::new(0, 0), blur_sigma, 0.0, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+        adjusted = blurred;
+    }
+    if sharpen_amount > 0.0 {
+        // アンシャープマスク: src * (1 + a) - blur * a
+        let mut blurred = core::Mat::default();
+        imgproc::gaussian_blur(&adjusted, &mut blurred, core::Size::new(0, 0), 1.0, 0.0, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+        let mut sharpened = core::Mat::default();
+        core::add_weighted(&adjusted, 1.0 + sharpen_amount, &blurred, -sharpen_amount, 0.0, &mut sharpened, -1).map_err(|e| e.to_string())?;
+        adjusted = sharpened;
+    }
+
+    // 3. ノイズ: ぼかし後に残っているノイズとの差分だけ足す
+    let noise_sigma = match options.noise_sigma {
+        Some(sigma) => sigma,
+        None => {
+            let remaining = estimate_noise(&to_gray(&adjusted)?)?;
+            (target_noise.powi(2) - remaining.powi(2)).max(0.0).sqrt()
+        }
+    };
+    if noise_sigma > 0.0 {
+        // 輝度ノイズ（全チャンネル同じ値）にしてカラーノイズっぽさを避ける
+        let mut noise = core::Mat::new_rows_cols_with_default(adjusted.rows(), adjusted.cols(), core::CV_32FC1, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+        core::randn(&mut noise, &core::Scalar::all(0.0), &core::Scalar::all(noise_sigma)).map_err(|e| e.to_string())?;
+        let mut noise_3ch = core::Mat::default();
+        imgproc::cvt_color(&noise, &mut noise_3ch, imgproc::COLOR_GRAY2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+        let mut adjusted_f32 = core::Mat::default();
+        adjusted.convert_to(&mut adjusted_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
+        let mut noisy = core::Mat::default();
+        core::add(&adjusted_f32, &noise_3ch, &mut noisy, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+        noisy.convert_to(&mut adjusted, core::CV_8U, 1.0, 0.0).map_err(|e| e.to_string())?;
+    }
+
+    adjusted.copy_to(dst).map_err(|e| e.to_string())?;
+
+    Ok(TextureAdjustment {
+        source_sharpness,
+        target_sharpness,
+        source_noise,
+        target_noise,
+        blur_sigma,
+        sharpen_amount,
+        noise_sigma,
+    })
+}
+
+// ノイズの標準偏差を推定（Immerkaerの方法）
+fn estimate_noise(gray: &core::Mat) -> Result<f64, String> {
+    let kernel = core::Mat::from_slice_2d(&[
+        [1.0f32, -2.0, 1.0],
+        [-2.0, 4.0, -2.0],
+        [1.0, -2.0, 1.0],
+    ]).map_err(|e| e.to_string())?;
+
+    let mut response = core::Mat::default();
+    imgproc::filter_2d(gray, &mut response, core::CV_32F, &kernel, core::Point::new(-1, -1), 0.0, core::BORDER_DEFAULT).map_err(|e| e.to_string())?;
+    let sum_abs = core::norm(&response, core::NORM_L1, &core::Mat::default()).map_err(|e| e.to_string())?;
+
+    let w = (gray.cols() - 2).max(1) as f64;
+    let h = (gray.rows() - 2).max(1) as f64;
+    Ok(sum_abs * (std::f64::consts::PI / 2.0).sqrt() / (6.0 * w * h))
+}
+
+// 色補正: ソース画像の肌色をターゲット画像の肌色に合わせる
+fn match_color(src: &core::Mat, target: &core::Mat, dst: &mut core::Mat, strength: f64) -> Result<(), String> {
+    // 肌色を抽出（YCrCbカラースペース使用）
+    let mut src_ycrcb = core::Mat::default();
+    let mut target_ycrcb = core::Mat::default();
+    imgproc::cvt_color(src, &mut src_ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    imgproc::cvt_color(target, &mut target_ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    // 肌色範囲でマスクを作成（Cr: 133-173, Cb: 77-127 あたりが肌色）
+    let mut src_skin_mask = core::Mat::default();
+    let mut target_skin_mask = core::Mat::default();
+    let lower_skin = core::Scalar::new(0.0, 133.0, 77.0, 0.0);
+    let upper_skin = core::Scalar::new(255.0, 173.0, 127.0, 0.0);
+    
+    core::in_range(&src_ycrcb, &lower_skin, &upper_skin, &mut src_skin_mask).map_err(|e| e.to_string())?;
+    core::in_range(&target_ycrcb, &lower_skin, &upper_skin, &mut target_skin_mask).map_err(|e| e.to_string())?;
+
+    // 肌色領域の平均色を計算
+    let src_skin_mean = core::mean(src, &src_skin_mask).map_err(|e| e.to_string())?;
+    let target_skin_mean = core::mean(target, &target_skin_mask).map_err(|e| e.to_string())?;
+
+    // 肌色の差分を計算（strengthで補正強度を調整）
+    let color_shift = [
+        (target_skin_mean[0] - src_skin_mean[0]) * strength,
+        (target_skin_mean[1] - src_skin_mean[1]) * strength,
+        (target_skin_mean[2] - src_skin_mean[2]) * strength,
+    ];
+
+    // 変換後の画像を作成
+    src.copy_to(dst).map_err(|e| e.to_string())?;
+    
+    // 色差分を加算（輝度補正は削除して色シフトのみ）
+    let mut dst_f32 = core::Mat::default();
+    dst.convert_to(&mut dst_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
+    
+    let scalar_shift = core::Scalar::new(color_shift[0], color_shift[1], color_shift[2], 0.0);
+    let mut shifted = core::Mat::default();
+    core::add(&dst_f32, &scalar_shift, &mut shifted, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    
+    shifted.convert_to(dst, core::CV_8U, 1.0, 0.0).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// より自然なブレンディング（高速版）
+fn blend_with_feathering(src: &core::Mat, dst: &mut core::Mat, mask: &core::Mat, x: i32, y: i32) -> Result<(), String> {
+    // マスクをぼかして境界を柔らかく（控えめに）
+    let mut feathered_mask = core::Mat::default();
+    imgproc::gaussian_blur(
+        mask,
+        &mut feathered_mask,
+        core::Size::new(9, 9),
+        2.5,
+        0.0,
+        core::BORDER_DEFAULT,
+        core::AlgorithmHint::ALGO_HINT_DEFAULT
+    ).map_err(|e| e.to_string())?;
+
+    blend_with_alpha(src, dst, &feathered_mask, x, y)
+}
+
+// 8bitのアルファマスクでsrcをdstの(x, y)に合成する（ぼかしなし）
+fn blend_with_alpha(src: &core::Mat, dst: &mut core::Mat, mask: &core::Mat, x: i32, y: i32) -> Result<(), String> {
+    let height = src.rows();
+    let width = src.cols();
+
+    // マスクを3チャンネルに変換してアルファブレンディング用に準備
+    let mut mask_3ch = core::Mat::default();
+    imgproc::cvt_color(mask, &mut mask_3ch, imgproc::COLOR_GRAY2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    
+    let mut mask_f32 = core::Mat::default();
+    mask_3ch.convert_to(&mut mask_f32, core::CV_32F, 1.0 / 255.0, 0.0).map_err(|e| e.to_string())?;
+
+    // ROI（関心領域）を取得
+    let roi_rect = core::Rect::new(x, y, width, height);
+    let dst_roi = core::Mat::roi(dst, roi_rect).map_err(|e| e.to_string())?;
+    
+    // 高速ブレンディング: dst_roi = src * mask + dst_roi * (1 - mask)
+    let mut src_f32 = core::Mat::default();
+    let mut dst_roi_f32 = core::Mat::default();
+    src.convert_to(&mut src_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
+    dst_roi.convert_to(&mut dst_roi_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
+    
+    let mut src_masked = core::Mat::default();
+    core::multiply(&src_f32, &mask_f32, &mut src_masked, 1.0, -1).map_err(|e| e.to_string())?;
+    
+    let mut inv_mask = core::Mat::default();
+    core::subtract(&core::Scalar::all(1.0), &mask_f32, &mut inv_mask, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
     
-    // 元の輝度とマッチング後の輝度をブレンド（30%のみ適用）
-    let mut matched_l = core::Mat::default();
-    core::add_weighted(&src_l, 0.7, &fully_matched_l, 0.3, 0.0, &mut matched_l, -1).map_err(|e| e.to_string())?;
+    let mut dst_masked = core::Mat::default();
+    core::multiply(&dst_roi_f32, &inv_mask, &mut dst_masked, 1.0, -1).map_err(|e| e.to_string())?;
     
-    // マッチングしたLチャンネルをセット
-    src_channels.set(0, matched_l).map_err(|e| e.to_string())?;
+    let mut blended_f32 = core::Mat::default();
+    core::add(&src_masked, &dst_masked, &mut blended_f32, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
     
-    // チャンネル結合
-    let mut matched_lab = core::Mat::default();
-    core::merge(&src_channels, &mut matched_lab).map_err(|e| e.to_string())?;
+    let mut blended = core::Mat::default();
+    blended_f32.convert_to(&mut blended, core::CV_8U, 1.0, 0.0).map_err(|e| e.to_string())?;
     
-    // BGRに戻す
-    imgproc::cvt_color(&matched_lab, dst, imgproc::COLOR_Lab2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    // 結果をdstにコピー
+    blended.copy_to(&mut core::Mat::roi_mut(dst, roi_rect).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+
+    Ok(())
+}
+
+// ---- 顔ライブラリ（アプリのデータフォルダに library.json + PNG で保存） ----
+
+// ライブラリの1件
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+struct LibraryEntry {
+    id: String,
+    created_at: u64,                  // 保存日時（UNIX秒）
+    source_path: String,              // 切り抜き元の画像
+    rect: FaceRect,                   // 元画像での顔矩形
+    canvas: FaceRect,                 // 元画像での切り抜き範囲（画像ファイルはこの範囲）
+    landmarks: Option<Vec<[f32; 2]>>, // 元画像座標の68点ランドマーク
+    #[serde(alias = "embedding")]
+    appearance_vector: Vec<f32>,      // 見た目の特徴（正規化した32x32の輝度。顔認識の埋め込みではない）
+    tags: Vec<String>,
+    image_file: String,               // 切り抜き画像（RGBA PNG、ライブラリフォルダ内のファイル名）
+    mask_file: String,                // マスク（グレースケールPNG）
+}
+
+// library.jsonの読み書きを直列化する
+static LIBRARY_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+#[derive(serde::Serialize)]
+struct LibraryAddResult {
+    added: Vec<LibraryEntry>,
+    warnings: Vec<String>, // 省略した処理とその理由（ランドマークのモデルが無いなど）
+}
+
+// 画像内の顔を切り抜いてライブラリに保存
+#[tauri::command]
+fn library_add(app: tauri::AppHandle, path: String, tags: Option<Vec<String>>, detect_options: Option<DetectOptions>) -> Result<LibraryAddResult, String> {
+    let dir = library_dir(&app)?;
+    let tags = normalize_tags(tags.unwrap_or_default());
+
+    let img = imgcodecs::imread(&path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "画像の読み込みに失敗")?;
+    let faces = detect_faces(&img, &detect_options.unwrap_or_default())?.faces;
+    if faces.is_empty() {
+        return Err("顔が検出されませんでした".to_string());
+    }
+
+    let face_rects: Vec<core::Rect> = faces.iter().map(|f| f.rect).collect();
+    let mut warnings = Vec::new();
+    let all_landmarks: Vec<Option<Vec<core::Point2f>>> = match load_facemark().and_then(|mut facemark| detect_landmarks(&mut facemark, &img, &face_rects)) {
+        Ok(all) => all.into_iter().map(Some).collect(),
+        Err(e) => {
+            warnings.push(format!("ランドマーク無しで保存しました: {}", e));
+            vec![None; faces.len()]
+        }
+    };
+
+    let mut added = Vec::new();
+    for (rect, landmarks) in face_rects.iter().zip(all_landmarks.iter()) {
+        let (canvas, head, mask) = extract_face_with_mask(&img, rect, &ExtractOptions::for_swap())?;
+
+        let id = new_unique_id();
+        let (image_file, mask_file) = library_file_names(&id);
+        write_image(&dir.join(&image_file), &merge_alpha(&head, &mask)?)?;
+        write_image(&dir.join(&mask_file), &mask)?;
+
+        added.push(LibraryEntry {
+            id,
+            created_at: unix_now(),
+            source_path: path.clone(),
+            rect: (*rect).into(),
+            canvas: canvas.into(),
+            landmarks: landmarks.as_ref().map(|points| points.iter().map(|p| [p.x, p.y]).collect()),
+            appearance_vector: appearance_vector(&img, *rect, landmarks.as_deref())?,
+            tags: tags.clone(),
+            image_file,
+            mask_file,
+        });
+    }
+
+    let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut entries = load_library(&dir)?;
+    entries.extend(added.iter().cloned());
+    save_library(&dir, &entries)?;
+
+    Ok(LibraryAddResult { added, warnings })
+}
+
+// タグ（全て含むもの）と文字列（タグか元画像パスに含むもの）で検索。どちらも省略すれば全件
+#[tauri::command]
+fn library_list(app: tauri::AppHandle, query: Option<String>, tags: Option<Vec<String>>) -> Result<Vec<LibraryEntry>, String> {
+    let dir = library_dir(&app)?;
+    let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+    let entries = load_library(&dir)?;
+
+    let tags = normalize_tags(tags.unwrap_or_default());
+    let query = query.map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
+
+    Ok(entries.into_iter()
+        .filter(|entry| tags.iter().all(|t| entry.tags.contains(t)))
+        .filter(|entry| match &query {
+            Some(q) => entry.tags.iter().any(|t| t.contains(q.as_str())) || entry.source_path.to_lowercase().contains(q.as_str()),
+            None => true,
+        })
+        .collect())
+}
+
+// タグを付け替える
+#[tauri::command]
+fn library_set_tags(app: tauri::AppHandle, id: String, tags: Vec<String>) -> Result<LibraryEntry, String> {
+    let dir = library_dir(&app)?;
+    let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut entries = load_library(&dir)?;
+
+    let entry = entries.iter_mut().find(|e| e.id == id).ok_or("ライブラリに見つかりません")?;
+    entry.tags = normalize_tags(tags);
+    let updated = entry.clone();
+
+    save_library(&dir, &entries)?;
+    Ok(updated)
+}
+
+// 1件削除（画像ファイルも消す）
+#[tauri::command]
+fn library_remove(app: tauri::AppHandle, id: String) -> Result<(), String> {
+    let dir = library_dir(&app)?;
+    let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut entries = load_library(&dir)?;
+
+    let index = entries.iter().position(|e| e.id == id).ok_or("ライブラリに見つかりません")?;
+    let entry = entries.remove(index);
+    // library.jsonが書き換えられていてもライブラリフォルダの外は消さない
+    for name in [&entry.image_file, &entry.mask_file] {
+        if let Ok(path) = library_file(&dir, name) {
+            std::fs::remove_file(path).ok();
+        }
+    }
+
+    save_library(&dir, &entries)
+}
+
+// ライブラリ全体（library.json + 画像）をフォルダに書き出す
+#[tauri::command]
+fn library_export(app: tauri::AppHandle, dest_dir: String) -> Result<usize, String> {
+    let dir = library_dir(&app)?;
+    let dest = std::path::Path::new(&dest_dir);
+    std::fs::create_dir_all(dest).map_err(|e| format!("出力フォルダを作成できません: {}", e))?;
+
+    let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+    let entries = load_library(&dir)?;
+    for entry in &entries {
+        copy_file(&library_file(&dir, &entry.image_file)?, &library_file(dest, &entry.image_file)?)?;
+        copy_file(&library_file(&dir, &entry.mask_file)?, &library_file(dest, &entry.mask_file)?)?;
+    }
+    save_library(dest, &entries)?;
+
+    Ok(entries.len())
+}
+
+// 書き出したライブラリを取り込む（同じIDのものは既存を優先してスキップ）
+// 取り込むlibrary.jsonは信用せず、IDを検査してファイル名はIDから作り直す
+#[tauri::command]
+fn library_import(app: tauri::AppHandle, src_dir: String) -> Result<usize, String> {
+    let dir = library_dir(&app)?;
+    let src = std::path::Path::new(&src_dir);
+    if !src.join("library.json").exists() {
+        return Err("library.jsonが見つかりません".to_string());
+    }
+
+    let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+    let incoming = load_library(src)?;
+    let mut entries = load_library(&dir)?;
+
+    // 途中で失敗してファイルだけ残らないよう、コピーする前にすべて検証する
+    let mut pending: Vec<(LibraryEntry, std::path::PathBuf, std::path::PathBuf)> = Vec::new();
+    for entry in incoming {
+        if !is_valid_library_id(&entry.id) {
+            return Err(format!("不正なIDです: {}", entry.id));
+        }
+        let source_image = library_file(src, &entry.image_file)?;
+        let source_mask = library_file(src, &entry.mask_file)?;
+        if entries.iter().chain(pending.iter().map(|(e, _, _)| e)).any(|e: &LibraryEntry| e.id == entry.id) {
+            continue;
+        }
+        for path in [&source_image, &source_mask] {
+            if !path.is_file() {
+                return Err(format!("ファイルが見つかりません: {}", path.display()));
+            }
+        }
+        pending.push((entry, source_image, source_mask));
+    }
+
+    let imported = pending.len();
+    for (mut entry, source_image, source_mask) in pending {
+        let (image_file, mask_file) = library_file_names(&entry.id);
+        copy_file(&source_image, &library_file(&dir, &image_file)?)?;
+        copy_file(&source_mask, &library_file(&dir, &mask_file)?)?;
+        entry.image_file = image_file;
+        entry.mask_file = mask_file;
+        entries.push(entry);
+    }
+    save_library(&dir, &entries)?;
+
+    Ok(imported)
+}
+
+// ライブラリのIDから切り抜き画像（RGBA PNG）のパスを引く
+fn library_image_path(app: &tauri::AppHandle, id: &str) -> Result<String, String> {
+    let dir = library_dir(app)?;
+    let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+    let entry = load_library(&dir)?.into_iter().find(|e| e.id == id).ok_or("ライブラリに見つかりません")?;
+    Ok(library_file(&dir, &entry.image_file)?.to_string_lossy().to_string())
+}
+
+// IDから切り抜き画像とマスクのファイル名を作る
+fn library_file_names(id: &str) -> (String, String) {
+    (format!("{}.png", id), format!("{}_mask.png", id))
+}
+
+// new_unique_idが作る形式（英数字と-_のみ）か
+fn is_valid_library_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// ライブラリフォルダ内のファイルのパス。ディレクトリ区切りや..を含む名前は拒否する
+fn library_file(dir: &std::path::Path, name: &str) -> Result<std::path::PathBuf, String> {
+    let mut components = std::path::Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(std::path::Component::Normal(file)), None) if file.to_str() == Some(name) && !name.contains(['/', '\\']) => Ok(dir.join(name)),
+        _ => Err(format!("不正なファイル名です: {}", name)),
+    }
+}
+
+// アプリのデータフォルダ内のライブラリフォルダ（無ければ作る）
+fn library_dir(app: &tauri::AppHandle) -> Result<std::path::PathBuf, String> {
+    let dir = app.path().app_data_dir().map_err(|e| e.to_string())?.join("face_library");
+    std::fs::create_dir_all(&dir).map_err(|e| format!("ライブラリフォルダを作成できません: {}", e))?;
+    Ok(dir)
+}
+
+fn load_library(dir: &std::path::Path) -> Result<Vec<LibraryEntry>, String> {
+    let path = dir.join("library.json");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let json = std::fs::read_to_string(&path).map_err(|e| format!("library.jsonを読み込めません: {}", e))?;
+    serde_json::from_str(&json).map_err(|e| format!("library.jsonが壊れています: {}", e))
+}
+
+fn save_library(dir: &std::path::Path, entries: &[LibraryEntry]) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(entries).map_err(|e| e.to_string())?;
+    std::fs::write(dir.join("library.json"), json).map_err(|e| format!("library.jsonを保存できません: {}", e))
+}
+
+fn copy_file(from: &std::path::Path, to: &std::path::Path) -> Result<(), String> {
+    std::fs::copy(from, to).map(|_| ()).map_err(|e| format!("{}をコピーできません: {}", from.display(), e))
+}
+
+fn write_image(path: &std::path::Path, img: &core::Mat) -> Result<(), String> {
+    let written = imgcodecs::imwrite(&path.to_string_lossy(), img, &core::Vector::new()).map_err(|e| e.to_string())?;
+    if !written {
+        return Err(format!("{}を保存できません", path.display()));
+    }
+    Ok(())
+}
+
+// タグは前後の空白を除いて小文字にし、重複と空文字を除く
+fn normalize_tags(tags: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = tags.into_iter()
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect();
+    normalized.sort();
+    normalized.dedup();
+    normalized
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// 時刻（ナノ秒）と連番から作る一意なID
+fn new_unique_id() -> String {
+    static COUNTER: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    format!("{:x}{:04x}", nanos, count & 0xffff)
+}
+
+// 軽量な見た目の特徴ベクトル: 両目尻で揃えた顔のグレースケール32x32を平均0・ノルム1にしたもの
+// （ランドマークが無ければ検出矩形をそのまま縮小）。画素の並びなので同一人物の判定には使えない
+fn appearance_vector(img: &core::Mat, rect: core::Rect, landmarks: Option<&[core::Point2f]>) -> Result<Vec<f32>, String> {
+    const SIZE: i32 = 32;
+    let size = core::Size::new(SIZE, SIZE);
+
+    let mut chip = core::Mat::default();
+    match landmarks {
+        Some(points) if points.len() >= 68 => {
+            let transform = similarity_from_two_points(
+                points[36], points[45],
+                core::Point2f::new(0.25 * SIZE as f32, 0.35 * SIZE as f32),
+                core::Point2f::new(0.75 * SIZE as f32, 0.35 * SIZE as f32),
+            )?;
+            imgproc::warp_affine(img, &mut chip, &transform, size, imgproc::INTER_AREA, core::BORDER_REPLICATE, core::Scalar::default())
+                .map_err(|e| e.to_string())?;
+        }
+        _ => {
+            let roi = core::Mat::roi(img, rect).map_err(|e| e.to_string())?;
+            imgproc::resize(&roi, &mut chip, size, 0.0, 0.0, imgproc::INTER_AREA).map_err(|e| e.to_string())?;
+        }
+    }
+
+    let mut gray = core::Mat::default();
+    imgproc::cvt_color(&chip, &mut gray, imgproc::COLOR_BGR2GRAY, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    // 照明の影響を減らす
+    let mut equalized = core::Mat::default();
+    imgproc::equalize_hist(&gray, &mut equalized).map_err(|e| e.to_string())?;
+
+    let mut values: Vec<f32> = equalized.data_bytes().map_err(|e| e.to_string())?.iter().map(|&v| v as f32).collect();
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    values.iter_mut().for_each(|v| *v -= mean);
+    let norm = values.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        values.iter_mut().for_each(|v| *v /= norm);
+    }
+
+    Ok(values)
+}
+
+// ---- マスクの対話的な修正 ----
+
+// process_faceの1顔分のGrabCutの状態
+struct MaskSession {
+    id: String,
+    canvas: core::Mat,    // 切り抜き前のキャンバス画像
+    canvas_rect: core::Rect, // キャンバスの元画像内での位置
+    state: GrabCutState,
+    options: ExtractOptions, // process_faceと同じ反復回数と仕上げを修正後にも使う
+    last_used: std::time::Instant,
+}
+
+// 一定時間使われなかったセッションは破棄する
+const MASK_SESSION_TTL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+// 保持するセッションの上限（超えたら古い順に破棄）
+const MAX_MASK_SESSIONS: usize = 32;
+
+static MASK_SESSIONS: std::sync::Mutex<Vec<MaskSession>> = std::sync::Mutex::new(Vec::new());
+
+// ユーザーが描いた修正線（座標はprocess_faceの切り抜き画像の画素座標）
+#[derive(serde::Deserialize)]
+struct MaskStroke {
+    points: Vec<[f32; 2]>,
+    width: i32,          // ブラシの太さ（px）
+    label: StrokeLabel,
+}
+
+#[derive(serde::Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum StrokeLabel {
+    Foreground, // 必ず残す（GC_FGD）
+    Background, // 必ず消す（GC_BGD）
+}
+
+#[derive(serde::Serialize)]
+struct RefineResult {
+    base64: String,         // 修正後の透過画像
+    foreground_pixels: i32, // 修正後のマスクの前景画素数
+}
+
+// 修正線をGrabCutのラベルに書き込み、保存したモデルから再実行する
+#[tauri::command]
+fn refine_mask(session_id: String, strokes: Vec<MaskStroke>) -> Result<RefineResult, String> {
+    // GrabCut中に他のセッションを止めないよう、取り出してからロックを外す
+    let mut session = {
+        let mut sessions = MASK_SESSIONS.lock().map_err(|e| e.to_string())?;
+        expire_mask_sessions(&mut sessions);
+        let index = sessions.iter().position(|s| s.id == session_id)
+            .ok_or("セッションが見つかりません（期限切れの可能性があります）")?;
+        sessions.remove(index)
+    };
+
+    // 失敗してもセッションは戻す（もう一度修正できるように）
+    let result = refine_session(&mut session, &strokes);
+    session.last_used = std::time::Instant::now();
+    MASK_SESSIONS.lock().map_err(|e| e.to_string())?.push(session);
+    result
+}
+
+fn refine_session(session: &mut MaskSession, strokes: &[MaskStroke]) -> Result<RefineResult, String> {
+    for stroke in strokes {
+        let value = match stroke.label {
+            StrokeLabel::Foreground => imgproc::GC_FGD,
+            StrokeLabel::Background => imgproc::GC_BGD,
+        };
+        let color = core::Scalar::all(value as f64);
+        // 縮小してGrabCutしたセッションでは作業解像度の座標に直す
+        let scale = session.state.scale;
+        let thickness = ((stroke.width as f64 * scale).round() as i32).max(1);
+        let points: core::Vector<core::Point> = stroke.points.iter()
+            .map(|p| core::Point::new((p[0] as f64 * scale).round() as i32, (p[1] as f64 * scale).round() as i32))
+            .collect();
+
+        // ラベルなのでアンチエイリアスはかけない
+        if points.len() == 1 {
+            let center = points.get(0).map_err(|e| e.to_string())?;
+            imgproc::circle(&mut session.state.labels, center, (thickness / 2).max(1), color, -1, imgproc::LINE_8, 0)
+                .map_err(|e| e.to_string())?;
+        } else if points.len() > 1 {
+            let mut lines = core::Vector::<core::Vector<core::Point>>::new();
+            lines.push(points);
+            imgproc::polylines(&mut session.state.labels, &lines, false, color, thickness, imgproc::LINE_8, 0)
+                .map_err(|e| e.to_string())?;
+        }
+    }
+
+    // GC_EVALは保存したGMMから続きを計算する（GC_INIT_WITH_MASKだとGMMを作り直してしまう）
+    // 修正線のGC_FGD / GC_BGDはそのまま固定される。反復回数と収束判定は初回と同じ設定
+    let state = &mut session.state;
+    iterate_grab_cut(&state.enhanced, &mut state.labels, &mut state.bgd, &mut state.fgd, 0, &session.options)?;
+
+    let mask = labels_to_mask(&state.labels, state.scale, &session.canvas)?;
+    let base64 = encode_cutout(&session.canvas, &mask, session.options.matting.as_ref())?;
+    let foreground_pixels = core::count_non_zero(&mask).map_err(|e| e.to_string())?;
+
+    Ok(RefineResult { base64, foreground_pixels })
+}
+
+// 不要になったセッションをすぐに解放する
+#[tauri::command]
+fn close_mask_session(session_id: String) -> Result<(), String> {
+    let mut sessions = MASK_SESSIONS.lock().map_err(|e| e.to_string())?;
+    sessions.retain(|s| s.id != session_id);
+    Ok(())
+}
+
+fn store_mask_session(canvas: core::Mat, canvas_rect: core::Rect, state: GrabCutState, options: &ExtractOptions) -> Result<String, String> {
+    let id = new_unique_id();
+    let mut sessions = MASK_SESSIONS.lock().map_err(|e| e.to_string())?;
+    expire_mask_sessions(&mut sessions);
+    if sessions.len() >= MAX_MASK_SESSIONS {
+        sessions.sort_by_key(|s| s.last_used);
+        let excess = sessions.len() + 1 - MAX_MASK_SESSIONS;
+        sessions.drain(..excess);
+    }
+    sessions.push(MaskSession { id: id.clone(), canvas, canvas_rect, state, options: options.clone(), last_used: std::time::Instant::now() });
+    Ok(id)
+}
+
+fn expire_mask_sessions(sessions: &mut Vec<MaskSession>) {
+    sessions.retain(|s| s.last_used.elapsed() < MASK_SESSION_TTL);
+}
+
+// ---- 切り抜いた頭の合成 ----
+
+// 背景と配置の設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct PlacementOptions {
+    background: BackgroundStyle,
+    color: [u8; 3],                   // 単色の色 / グラデーションの上端の色（RGB）
+    gradient_color: [u8; 3],          // グラデーションの下端の色（RGB）
+    background_path: Option<String>,  // image / blurの元画像
+    blur_sigma: f64,                  // blurのぼかし（σ）
+    width: Option<i32>,               // solid / gradientの出力サイズ（省略時は切り抜きと同じ）
+    height: Option<i32>,
+    x: Option<f64>,                   // 切り抜きの中心を置く位置（省略時はセッションなら元の位置、それ以外は中央）
+    y: Option<f64>,
+    scale: f64,
+    rotation: f64,                    // 回転（度、反時計回り）
+    edge_match: f64,                  // 縁の色を新しい背景に寄せる強さ（0で無効、1で最大）
+    edge_width: i32,                  // 縁として扱う幅（px）
+}
+
+// 背景の種類
+#[derive(serde::Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum BackgroundStyle {
+    Solid,    // 単色
+    Gradient, // 上から下へのグラデーション
+    Blur,     // 画像をぼかしたもの（元写真をぼかせば背景ぼけ風）
+    Image,    // 別の画像
+}
+
+impl Default for PlacementOptions {
+    fn default() -> Self {
+        PlacementOptions {
+            background: BackgroundStyle::Solid,
+            color: [255, 255, 255],
+            gradient_color: [200, 200, 200],
+            background_path: None,
+            blur_sigma: 15.0,
+            width: None,
+            height: None,
+            x: None,
+            y: None,
+            scale: 1.0,
+            rotation: 0.0,
+            edge_match: 0.0,
+            edge_width: 6,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct PlacementResult {
+    base64: String,              // 合成結果（PNG）
+    output_path: Option<String>, // 保存先
+    width: i32,
+    height: i32,
+}
+
+// process_faceの切り抜き（RGBA画像のパスかセッションID）を新しい背景に合成する
+#[tauri::command]
+fn composite_extracted(
+    cutout_path: Option<String>,
+    session_id: Option<String>,
+    output_path: Option<String>,
+    options: Option<PlacementOptions>,
+) -> Result<PlacementResult, String> {
+    let options = options.unwrap_or_default();
+
+    // 1. 切り抜き（色とアルファ）。セッションなら最新の修正を反映し、元の位置も分かる
+    let (colors, alpha, origin) = match (&session_id, &cutout_path) {
+        (Some(id), _) => {
+            let (colors, alpha, canvas_rect) = session_cutout(id)?;
+            (colors, alpha, Some(canvas_rect))
+        }
+        (None, Some(path)) => {
+            let (colors, alpha) = read_with_alpha(path)?;
+            (colors, alpha.ok_or("切り抜き画像にアルファがありません")?, None)
+        }
+        (None, None) => return Err("切り抜き画像かセッションIDを指定してください".to_string()),
+    };
+
+    // 2. 背景
+    let mut background = create_background(&options, colors.size().map_err(|e| e.to_string())?)?;
+    let bg_size = background.size().map_err(|e| e.to_string())?;
+
+    // 3. 配置（切り抜きの中心を(x, y)に合わせて拡大・回転）
+    let default_center = match origin {
+        Some(rect) if matches!(options.background, BackgroundStyle::Blur | BackgroundStyle::Image) => {
+            (rect.x as f64 + rect.width as f64 / 2.0, rect.y as f64 + rect.height as f64 / 2.0)
+        }
+        _ => (bg_size.width as f64 / 2.0, bg_size.height as f64 / 2.0),
+    };
+    let center_x = options.x.unwrap_or(default_center.0);
+    let center_y = options.y.unwrap_or(default_center.1);
+
+    let cutout_center = core::Point2f::new(colors.cols() as f32 / 2.0, colors.rows() as f32 / 2.0);
+    let mut transform = imgproc::get_rotation_matrix_2d(cutout_center, options.rotation, options.scale).map_err(|e| e.to_string())?;
+    *transform.at_2d_mut::<f64>(0, 2).map_err(|e| e.to_string())? += center_x - cutout_center.x as f64;
+    *transform.at_2d_mut::<f64>(1, 2).map_err(|e| e.to_string())? += center_y - cutout_center.y as f64;
+
+    let mut placed = core::Mat::default();
+    let mut placed_alpha = core::Mat::default();
+    imgproc::warp_affine(&colors, &mut placed, &transform, bg_size, imgproc::INTER_LINEAR, core::BORDER_REPLICATE, core::Scalar::default()).map_err(|e| e.to_string())?;
+    imgproc::warp_affine(&alpha, &mut placed_alpha, &transform, bg_size, imgproc::INTER_LINEAR, core::BORDER_CONSTANT, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+
+    // 4. 縁の色を新しい背景に寄せる（元の背景色のにじみを目立たなくする）
+    if options.edge_match > 0.0 {
+        placed = match_edge_colors(&placed, &placed_alpha, &background, options.edge_match.min(1.0), options.edge_width.max(1))?;
+    }
+
+    // 5. アルファ合成
+    blend_with_alpha(&placed, &mut background, &placed_alpha, 0, 0)?;
+
+    if let Some(path) = &output_path {
+        write_image(std::path::Path::new(path), &background)?;
+    }
+
+    Ok(PlacementResult {
+        base64: encode_png_base64(&background)?,
+        output_path,
+        width: bg_size.width,
+        height: bg_size.height,
+    })
+}
+
+// セッションの最新のマスクで切り抜きを作り直す（色, アルファ, キャンバスの位置）
+fn session_cutout(session_id: &str) -> Result<(core::Mat, core::Mat, core::Rect), String> {
+    let (canvas, canvas_rect, labels, scale, matting) = {
+        let mut sessions = MASK_SESSIONS.lock().map_err(|e| e.to_string())?;
+        expire_mask_sessions(&mut sessions);
+        let session = sessions.iter_mut().find(|s| s.id == session_id)
+            .ok_or("セッションが見つかりません（期限切れの可能性があります）")?;
+        session.last_used = std::time::Instant::now();
+        (session.canvas.clone(), session.canvas_rect, session.state.labels.clone(), session.state.scale, session.options.matting.clone())
+    };
+    let mask = labels_to_mask(&labels, scale, &canvas)?;
+    let (colors, alpha) = finish_cutout(&canvas, &mask, matting.as_ref())?;
+    Ok((colors, alpha, canvas_rect))
+}
+
+fn create_background(options: &PlacementOptions, cutout_size: core::Size) -> Result<core::Mat, String> {
+    let size = core::Size::new(
+        options.width.unwrap_or(cutout_size.width).max(1),
+        options.height.unwrap_or(cutout_size.height).max(1),
+    );
+    let bgr = |c: [u8; 3]| core::Scalar::new(c[2] as f64, c[1] as f64, c[0] as f64, 0.0);
+
+    match options.background {
+        BackgroundStyle::Solid => {
+            core::Mat::new_size_with_default(size, core::CV_8UC3, bgr(options.color)).map_err(|e| e.to_string())
+        }
+        BackgroundStyle::Gradient => {
+            let mut bg = core::Mat::new_size_with_default(size, core::CV_8UC3, bgr(options.color)).map_err(|e| e.to_string())?;
+            for y in 0..size.height {
+                let t = if size.height > 1 { y as f64 / (size.height - 1) as f64 } else { 0.0 };
+                let mix = |a: u8, b: u8| a as f64 * (1.0 - t) + b as f64 * t;
+                let color = core::Scalar::new(
+                    mix(options.color[2], options.gradient_color[2]),
+                    mix(options.color[1], options.gradient_color[1]),
+                    mix(options.color[0], options.gradient_color[0]),
+                    0.0,
+                );
+                let mut row = core::Mat::roi_mut(&mut bg, core::Rect::new(0, y, size.width, 1)).map_err(|e| e.to_string())?;
+                row.set_to(&color, &core::Mat::default()).map_err(|e| e.to_string())?;
+            }
+            Ok(bg)
+        }
+        BackgroundStyle::Blur | BackgroundStyle::Image => {
+            let path = options.background_path.as_ref().ok_or("背景画像を指定してください")?;
+            let img = imgcodecs::imread(path, imgcodecs::IMREAD_COLOR)
+                .map_err(|_| "背景画像の読み込みに失敗")?;
+            if img.empty() {
+                return Err("背景画像の読み込みに失敗".to_string());
+            }
+            if options.background == BackgroundStyle::Image {
+                return Ok(img);
+            }
+            let mut blurred = core::Mat::default();
+            imgproc::gaussian_blur(&img, &mut blurred, core::Size::new(0, 0), options.blur_sigma, options.blur_sigma, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+            Ok(blurred)
+        }
+    }
+}
+
+// アルファの縁（内側edge_widthの帯）の色を、ぼかした新しい背景の色へstrengthの割合まで寄せる
+fn match_edge_colors(fg: &core::Mat, alpha: &core::Mat, background: &core::Mat, strength: f64, edge_width: i32) -> Result<core::Mat, String> {
+    // 縁ほど1になる重み: (alpha - 削ったalpha) / 255
+    let k = imgproc::get_structuring_element(imgproc::MORPH_ELLIPSE, core::Size::new(edge_width * 2 + 1, edge_width * 2 + 1), core::Point::new(-1, -1)).map_err(|e| e.to_string())?;
+    let mut inner = core::Mat::default();
+    imgproc::erode(alpha, &mut inner, &k, core::Point::new(-1, -1), 1, core::BORDER_CONSTANT, core::Scalar::default()).map_err(|e| e.to_string())?;
+    let mut edge = core::Mat::default();
+    core::subtract(alpha, &inner, &mut edge, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    let mut edge_soft = core::Mat::default();
+    imgproc::gaussian_blur(&edge, &mut edge_soft, core::Size::new(0, 0), edge_width as f64 / 2.0, 0.0, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    let mut weight_3ch = core::Mat::default();
+    imgproc::cvt_color(&edge_soft, &mut weight_3ch, imgproc::COLOR_GRAY2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    let mut weight = core::Mat::default();
+    weight_3ch.convert_to(&mut weight, core::CV_32F, strength / 255.0, 0.0).map_err(|e| e.to_string())?;
+
+    // 周りの背景色
+    let mut bg_blur = core::Mat::default();
+    imgproc::gaussian_blur(background, &mut bg_blur, core::Size::new(0, 0), edge_width as f64 * 2.0, 0.0, core::BORDER_DEFAULT, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    // fg * (1 - w) + bg * w
+    let mut fg_f32 = core::Mat::default();
+    let mut bg_f32 = core::Mat::default();
+    fg.convert_to(&mut fg_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
+    bg_blur.convert_to(&mut bg_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
+    let mut inv_weight = core::Mat::default();
+    core::subtract(&core::Scalar::all(1.0), &weight, &mut inv_weight, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+    let mut fg_part = core::Mat::default();
+    let mut bg_part = core::Mat::default();
+    core::multiply(&fg_f32, &inv_weight, &mut fg_part, 1.0, -1).map_err(|e| e.to_string())?;
+    core::multiply(&bg_f32, &weight, &mut bg_part, 1.0, -1).map_err(|e| e.to_string())?;
+    let mut mixed_f32 = core::Mat::default();
+    core::add(&fg_part, &bg_part, &mut mixed_f32, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
+
+    let mut mixed = core::Mat::default();
+    mixed_f32.convert_to(&mut mixed, core::CV_8U, 1.0, 0.0).map_err(|e| e.to_string())?;
+    Ok(mixed)
+}
+
+// ---- 証明写真 ----
+
+// 証明写真の規格（id_photo_specs.jsonに組み込みの規格を定義）
+#[derive(serde::Serialize, serde::Deserialize, Clone)]
+struct IdPhotoSpec {
+    id: String,
+    name: String,
+    width_mm: f64,
+    height_mm: f64,
+    head_height_mm: f64,   // 頭頂からあごまでの高さ
+    crown_top_mm: f64,     // 写真の上端から頭頂までの余白
+    #[serde(default)]
+    eye_line_min_mm: Option<f64>, // 写真の下端から目の高さまでの許容範囲
+    #[serde(default)]
+    eye_line_max_mm: Option<f64>,
+    background: [u8; 3],   // 背景色（RGB）
+}
+
+// 証明写真の設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct IdPhotoOptions {
+    spec_id: String,              // 組み込み規格のID
+    spec: Option<IdPhotoSpec>,    // 指定するとspec_idの代わりにこの規格を使う
+    dpi: f64,
+    background: Option<[u8; 3]>,  // 規格の背景色を上書き（RGB）
+    output_path: Option<String>,  // TIFFならDPIも書き込む
+    sheet: bool,                  // 印刷用に並べたシートも作る
+    sheet_width_mm: f64,          // シートの大きさ（デフォルトはL判）
+    sheet_height_mm: f64,
+    sheet_gap_mm: f64,            // 写真の間隔
+    sheet_copies: Option<usize>,  // 並べる枚数（省略時は入るだけ）
+    sheet_path: Option<String>,
+    detect_options: DetectOptions,
+    extract: ExtractOptions,      // 頭と背景を分ける切り抜きの範囲
+}
+
+impl Default for IdPhotoOptions {
+    fn default() -> Self {
+        IdPhotoOptions {
+            spec_id: "jp_passport".to_string(),
+            spec: None,
+            dpi: 300.0,
+            background: None,
+            output_path: None,
+            sheet: false,
+            sheet_width_mm: 89.0,
+            sheet_height_mm: 127.0,
+            sheet_gap_mm: 2.0,
+            sheet_copies: None,
+            sheet_path: None,
+            detect_options: DetectOptions::default(),
+            extract: ExtractOptions { matting: Some(MattingOptions::default()), ..ExtractPreset::FullHead.options() },
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+struct IdPhotoResult {
+    base64: String,               // 証明写真（PNG）
+    sheet_base64: Option<String>, // 印刷用シート（PNG）
+    output_path: Option<String>,
+    sheet_path: Option<String>,
+    spec: IdPhotoSpec,
+    width: i32,                   // 出力サイズ（px）
+    height: i32,
+    rotation: f64,                // 目を水平にするために回した角度（度）
+    scale: f64,                   // 元画像からの拡大率（1を超えると解像度不足）
+    copies: usize,                // シートに並べた枚数
+    warnings: Vec<String>,
+}
+
+// 組み込みの証明写真の規格一覧
+#[tauri::command]
+fn list_id_photo_specs() -> Result<Vec<IdPhotoSpec>, String> {
+    builtin_id_photo_specs()
+}
+
+// 顔・ランドマーク・GrabCutのマスクから規格通りの証明写真を作る
+#[tauri::command]
+fn create_id_photo(path: String, options: Option<IdPhotoOptions>) -> Result<IdPhotoResult, String> {
+    let options = options.unwrap_or_default();
+    let mut spec = match &options.spec {
+        Some(spec) => spec.clone(),
+        None => builtin_id_photo_specs()?.into_iter().find(|s| s.id == options.spec_id)
+            .ok_or(format!("証明写真の規格が見つかりません: {}", options.spec_id))?,
+    };
+    if let Some(background) = options.background {
+        spec.background = background;
+    }
+    let mut warnings = Vec::new();
+
+    let img = imgcodecs::imread(&path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "画像の読み込みに失敗")?;
+    let detection = detect_faces(&img, &options.detect_options)?;
+    if detection.faces.len() > 1 {
+        warnings.push(format!("{}人の顔が検出されました。一番確かな顔を使います", detection.faces.len()));
+    }
+    let face = detection.faces.first().ok_or("顔が検出されませんでした")?.rect;
+    let landmarks = LazyFacemark::new().landmarks(&img, face)
+        .map_err(|e| format!("証明写真にはランドマークが必要です: {}", e))?;
+
+    // 1. 両目が水平になるように目の中点を中心に回転
+    let right_eye = landmark_center(&landmarks, LANDMARK_RIGHT_EYE);
+    let left_eye = landmark_center(&landmarks, LANDMARK_LEFT_EYE);
+    let eye_center = core::Point2f::new((right_eye.x + left_eye.x) / 2.0, (right_eye.y + left_eye.y) / 2.0);
+    let rotation = ((left_eye.y - right_eye.y) as f64).atan2((left_eye.x - right_eye.x) as f64).to_degrees();
+    let rotate = imgproc::get_rotation_matrix_2d(eye_center, rotation, 1.0).map_err(|e| e.to_string())?;
+    let mut leveled = core::Mat::default();
+    imgproc::warp_affine(&img, &mut leveled, &rotate, img.size().map_err(|e| e.to_string())?, imgproc::INTER_LINEAR, core::BORDER_REPLICATE, core::Scalar::default()).map_err(|e| e.to_string())?;
+    let leveled_points = transform_points(&landmarks, &rotate)?;
+    let face_center = transform_points(&[core::Point2f::new(face.x as f32 + face.width as f32 / 2.0, face.y as f32 + face.height as f32 / 2.0)], &rotate)?[0];
+    let leveled_face = core::Rect::new(
+        face_center.x as i32 - face.width / 2,
+        face_center.y as i32 - face.height / 2,
+        face.width,
+        face.height,
+    );
+
+    // 2. 頭（髪込み）のマスク。頭頂は目の上の範囲でマスクが始まる行
+    let (canvas, head_img, head_mask) = extract_face_with_mask(&leveled, &leveled_face, &options.extract)?;
+    let (colors, alpha) = finish_cutout(&head_img, &head_mask, options.extract.matting.as_ref())?;
+    let column = core::Rect::new(
+        (eye_center.x as i32 - face.width / 4 - canvas.x).max(0),
+        0,
+        (face.width / 2).min(canvas.width),
+        canvas.height,
+    );
+    let column = clip_rect(column, core::Size::new(canvas.width, canvas.height));
+    let head_top = imgproc::bounding_rect(&core::Mat::roi(&head_mask, column).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+    let crown_y = if head_top.height > 0 {
+        if head_top.y <= 1 && canvas.y == 0 {
+            warnings.push("頭頂が画像の上端で切れている可能性があります".to_string());
+        }
+        (canvas.y + head_top.y) as f64
+    } else {
+        warnings.push("頭頂を検出できなかったため顔の大きさから推定しました".to_string());
+        leveled_face.y as f64 - leveled_face.height as f64 * 0.5
+    };
+    let chin_y = leveled_points[8].y as f64; // 68点の8番があご先
+
+    // 3. 頭の高さと頭頂の位置が規格に合うように拡大・移動
+    let px_per_mm = options.dpi / 25.4;
+    let out_size = core::Size::new((spec.width_mm * px_per_mm).round() as i32, (spec.height_mm * px_per_mm).round() as i32);
+    let head_height = (chin_y - crown_y).max(1.0);
+    let scale = spec.head_height_mm * px_per_mm / head_height;
+    if scale > 1.0 {
+        warnings.push(format!("元画像の解像度が足りず{:.1}倍に拡大しています", scale));
+    }
+    if rotation.abs() > 10.0 {
+        warnings.push(format!("顔が{:.0}度傾いています。まっすぐ撮り直すのがおすすめです", rotation));
+    }
+    let tx = out_size.width as f64 / 2.0 - scale * eye_center.x as f64;
+    let mut ty = spec.crown_top_mm * px_per_mm - scale * crown_y;
+
+    // 目の高さ（下端から）が規格の範囲外なら上下にずらして範囲内に収める
+    // 回転は目の中点が中心なので、回転後もeye_centerの位置は変わらない
+    let eye_line_mm = spec.height_mm - (scale * eye_center.y as f64 + ty) / px_per_mm;
+    let eye_line_min = spec.eye_line_min_mm.unwrap_or(f64::NEG_INFINITY);
+    let eye_line_max = spec.eye_line_max_mm.unwrap_or(f64::INFINITY);
+    let adjusted_eye_line_mm = eye_line_mm.clamp(eye_line_min, eye_line_max.max(eye_line_min));
+    if adjusted_eye_line_mm != eye_line_mm {
+        ty -= (adjusted_eye_line_mm - eye_line_mm) * px_per_mm;
+        warnings.push(format!(
+            "目の高さが規格の範囲外（下端から{:.1}mm）だったため{:.1}mmに合わせました。頭頂の余白は{:.1}mmになります",
+            eye_line_mm, adjusted_eye_line_mm, (ty + scale * crown_y) / px_per_mm,
+        ));
+    }
+    let place = core::Mat::from_slice_2d(&[
+        [scale, 0.0, tx + scale * canvas.x as f64],
+        [0.0, scale, ty + scale * canvas.y as f64],
+    ]).map_err(|e| e.to_string())?;
+
+    let mut placed = core::Mat::default();
+    let mut placed_alpha = core::Mat::default();
+    imgproc::warp_affine(&colors, &mut placed, &place, out_size, imgproc::INTER_LANCZOS4, core::BORDER_REPLICATE, core::Scalar::default()).map_err(|e| e.to_string())?;
+    imgproc::warp_affine(&alpha, &mut placed_alpha, &place, out_size, imgproc::INTER_LINEAR, core::BORDER_CONSTANT, core::Scalar::all(0.0)).map_err(|e| e.to_string())?;
+
+    // 4. 無地の背景に合成
+    let background = core::Scalar::new(spec.background[2] as f64, spec.background[1] as f64, spec.background[0] as f64, 0.0);
+    let mut photo = core::Mat::new_size_with_default(out_size, core::CV_8UC3, background).map_err(|e| e.to_string())?;
+    blend_with_alpha(&placed, &mut photo, &placed_alpha, 0, 0)?;
+
+    if let Some(path) = &options.output_path {
+        write_image_with_dpi(std::path::Path::new(path), &photo, options.dpi)?;
+    }
+
+    // 5. 印刷用シート
+    let mut copies = 0;
+    let mut sheet_base64 = None;
+    if options.sheet {
+        let (sheet, placed_copies) = tile_id_photos(&photo, &options, px_per_mm)?;
+        if placed_copies == 0 {
+            warnings.push("シートに写真が1枚も入りません".to_string());
+        }
+        if let Some(path) = &options.sheet_path {
+            write_image_with_dpi(std::path::Path::new(path), &sheet, options.dpi)?;
+        }
+        copies = placed_copies;
+        sheet_base64 = Some(encode_png_base64(&sheet)?);
+    }
+
+    Ok(IdPhotoResult {
+        base64: encode_png_base64(&photo)?,
+        sheet_base64,
+        output_path: options.output_path.clone(),
+        sheet_path: if options.sheet { options.sheet_path.clone() } else { None },
+        spec,
+        width: out_size.width,
+        height: out_size.height,
+        rotation,
+        scale,
+        copies,
+        warnings,
+    })
+}
 
-    Ok(())
+fn builtin_id_photo_specs() -> Result<Vec<IdPhotoSpec>, String> {
+    serde_json::from_str(include_str!("../id_photo_specs.json")).map_err(|e| e.to_string())
 }
 
-// 色補正: ソース画像の肌色をターゲット画像の肌色に合わせる
-fn match_color(src: &core::Mat, target: &core::Mat, dst: &mut core::Mat, strength: f64) -> Result<(), String> {
-    // 肌色を抽出（YCrCbカラースペース使用）
-    let mut src_ycrcb = core::Mat::default();
-    let mut target_ycrcb = core::Mat::default();
-    imgproc::cvt_color(src, &mut src_ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
-    imgproc::cvt_color(target, &mut target_ycrcb, imgproc::COLOR_BGR2YCrCb, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+fn landmark_center(landmarks: &[core::Point2f], range: std::ops::Range<usize>) -> core::Point2f {
+    let n = range.len() as f32;
+    let (sx, sy) = landmarks[range].iter().fold((0.0, 0.0), |(sx, sy), p| (sx + p.x, sy + p.y));
+    core::Point2f::new(sx / n, sy / n)
+}
 
-    // 肌色範囲でマスクを作成（Cr: 133-173, Cb: 77-127 あたりが肌色）
-    let mut src_skin_mask = core::Mat::default();
-    let mut target_skin_mask = core::Mat::default();
-    let lower_skin = core::Scalar::new(0.0, 133.0, 77.0, 0.0);
-    let upper_skin = core::Scalar::new(255.0, 173.0, 127.0, 0.0);
-    
-    core::in_range(&src_ycrcb, &lower_skin, &upper_skin, &mut src_skin_mask).map_err(|e| e.to_string())?;
-    core::in_range(&target_ycrcb, &lower_skin, &upper_skin, &mut target_skin_mask).map_err(|e| e.to_string())?;
+// 2x3のアフィン行列（CV_64F）で点を変換する
+fn transform_points(points: &[core::Point2f], m: &core::Mat) -> Result<Vec<core::Point2f>, String> {
+    let a = |r: i32, c: i32| -> Result<f64, String> { m.at_2d::<f64>(r, c).map(|v| *v).map_err(|e| e.to_string()) };
+    let (m00, m01, m02, m10, m11, m12) = (a(0, 0)?, a(0, 1)?, a(0, 2)?, a(1, 0)?, a(1, 1)?, a(1, 2)?);
+    Ok(points.iter().map(|p| {
+        let (x, y) = (p.x as f64, p.y as f64);
+        core::Point2f::new((m00 * x + m01 * y + m02) as f32, (m10 * x + m11 * y + m12) as f32)
+    }).collect())
+}
 
-    // 肌色領域の平均色を計算
-    let src_skin_mean = core::mean(src, &src_skin_mask).map_err(|e| e.to_string())?;
-    let target_skin_mean = core::mean(target, &target_skin_mask).map_err(|e| e.to_string())?;
+// 写真をシートに格子状に並べ、切り取り線を引く（戻り値はシートと並べた枚数）
+fn tile_id_photos(photo: &core::Mat, options: &IdPhotoOptions, px_per_mm: f64) -> Result<(core::Mat, usize), String> {
+    let sheet_size = core::Size::new((options.sheet_width_mm * px_per_mm).round() as i32, (options.sheet_height_mm * px_per_mm).round() as i32);
+    let gap = (options.sheet_gap_mm * px_per_mm).round() as i32;
+    let mut sheet = core::Mat::new_size_with_default(sheet_size, core::CV_8UC3, core::Scalar::all(255.0)).map_err(|e| e.to_string())?;
 
-    // 肌色の差分を計算（strengthで補正強度を調整）
-    let color_shift = [
-        (target_skin_mean[0] - src_skin_mean[0]) * strength,
-        (target_skin_mean[1] - src_skin_mean[1]) * strength,
-        (target_skin_mean[2] - src_skin_mean[2]) * strength,
-    ];
+    let slots = id_photo_grid(sheet_size, core::Size::new(photo.cols(), photo.rows()), gap, options.sheet_copies);
+    for &rect in &slots {
+        photo.copy_to(&mut core::Mat::roi_mut(&mut sheet, rect).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+        let guide = core::Rect::new(rect.x - 1, rect.y - 1, rect.width + 2, rect.height + 2);
+        imgproc::rectangle(&mut sheet, guide, core::Scalar::all(190.0), 1, imgproc::LINE_8, 0).map_err(|e| e.to_string())?;
+    }
 
-    // 変換後の画像を作成
-    src.copy_to(dst).map_err(|e| e.to_string())?;
-    
-    // 色差分を加算（輝度補正は削除して色シフトのみ）
-    let mut dst_f32 = core::Mat::default();
-    dst.convert_to(&mut dst_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
-    
-    let scalar_shift = core::Scalar::new(color_shift[0], color_shift[1], color_shift[2], 0.0);
-    let mut shifted = core::Mat::default();
-    core::add(&dst_f32, &scalar_shift, &mut shifted, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
-    
-    shifted.convert_to(dst, core::CV_8U, 1.0, 0.0).map_err(|e| e.to_string())?;
+    Ok((sheet, slots.len()))
+}
+
+// シートに並べる写真の位置（行ごとに左から）。copiesを省略すると入るだけ並べる
+fn id_photo_grid(sheet_size: core::Size, photo_size: core::Size, gap: i32, copies: Option<usize>) -> Vec<core::Rect> {
+    let cols = ((sheet_size.width - gap) / (photo_size.width + gap)).max(0);
+    let rows = ((sheet_size.height - gap) / (photo_size.height + gap)).max(0);
+    let capacity = (cols * rows) as usize;
+    let copies = copies.map_or(capacity, |n| n.min(capacity));
+
+    // 格子全体をシートの中央に寄せる
+    let grid_w = cols * photo_size.width + (cols - 1).max(0) * gap;
+    let grid_h = rows * photo_size.height + (rows - 1).max(0) * gap;
+    let origin_x = (sheet_size.width - grid_w) / 2;
+    let origin_y = (sheet_size.height - grid_h) / 2;
+
+    (0..copies as i32).map(|i| core::Rect::new(
+        origin_x + (i % cols) * (photo_size.width + gap),
+        origin_y + (i / cols) * (photo_size.height + gap),
+        photo_size.width,
+        photo_size.height,
+    )).collect()
+}
+
+// TIFFなら解像度（DPI）も書き込む。他の形式は画素数だけがDPIに合わせてある
+fn write_image_with_dpi(path: &std::path::Path, img: &core::Mat, dpi: f64) -> Result<(), String> {
+    let is_tiff = path.extension()
+        .map(|ext| matches!(ext.to_string_lossy().to_lowercase().as_str(), "tif" | "tiff"))
+        .unwrap_or(false);
+    if !is_tiff {
+        return write_image(path, img);
+    }
 
+    let params = core::Vector::<i32>::from_slice(&[
+        imgcodecs::IMWRITE_TIFF_RESUNIT, 2, // インチ
+        imgcodecs::IMWRITE_TIFF_XDPI, dpi.round() as i32,
+        imgcodecs::IMWRITE_TIFF_YDPI, dpi.round() as i32,
+    ]);
+    let written = imgcodecs::imwrite(&path.to_string_lossy(), img, &params).map_err(|e| e.to_string())?;
+    if !written {
+        return Err(format!("{}を保存できません", path.display()));
+    }
     Ok(())
 }
 
-// より自然なブレンディング（高速版）
-fn blend_with_feathering(src: &core::Mat, dst: &mut core::Mat, mask: &core::Mat, x: i32, y: i32) -> Result<(), String> {
-    let height = src.rows();
-    let width = src.cols();
+// ---- 学習用の顔チップ ----
 
-    // マスクをぼかして境界を柔らかく（控えめに）
-    let mut feathered_mask = core::Mat::default();
-    imgproc::gaussian_blur(
-        mask,
-        &mut feathered_mask,
-        core::Size::new(9, 9),
-        2.5,
-        0.0,
-        core::BORDER_DEFAULT,
-        core::AlgorithmHint::ALGO_HINT_DEFAULT
-    ).map_err(|e| e.to_string())?;
+// 112x112の顔認識用テンプレート（ArcFace）の5点: 左目, 右目（画像上）, 鼻先, 口の左端, 右端
+const CHIP_TEMPLATE_112: [[f32; 2]; 5] = [
+    [38.2946, 51.6963],
+    [73.5318, 51.5014],
+    [56.0252, 71.7366],
+    [41.5493, 92.3655],
+    [70.7299, 92.2041],
+];
 
-    // マスクを3チャンネルに変換してアルファブレンディング用に準備
-    let mut mask_3ch = core::Mat::default();
-    imgproc::cvt_color(&feathered_mask, &mut mask_3ch, imgproc::COLOR_GRAY2BGR, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
-    
-    let mut mask_f32 = core::Mat::default();
-    mask_3ch.convert_to(&mut mask_f32, core::CV_32F, 1.0 / 255.0, 0.0).map_err(|e| e.to_string())?;
+// 顔チップ書き出しの設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct ChipOptions {
+    size: i32,               // 出力の一辺（112や256。テンプレートを拡大縮小する）
+    mask_alpha: bool,        // GrabCutのマスクをアルファにしたPNGで書き出す
+    json_manifest: bool,     // manifest.jsonを書き出す
+    csv_manifest: bool,      // manifest.csvを書き出す
+    detect_options: DetectOptions,
+    extract: ExtractOptions, // mask_alphaの時の切り抜き範囲
+}
 
-    // ROI（関心領域）を取得
-    let roi_rect = core::Rect::new(x, y, width, height);
-    let dst_roi = core::Mat::roi(dst, roi_rect).map_err(|e| e.to_string())?;
-    
-    // 高速ブレンディング: dst_roi = src * mask + dst_roi * (1 - mask)
-    let mut src_f32 = core::Mat::default();
-    let mut dst_roi_f32 = core::Mat::default();
-    src.convert_to(&mut src_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
-    dst_roi.convert_to(&mut dst_roi_f32, core::CV_32F, 1.0, 0.0).map_err(|e| e.to_string())?;
-    
-    let mut src_masked = core::Mat::default();
-    core::multiply(&src_f32, &mask_f32, &mut src_masked, 1.0, -1).map_err(|e| e.to_string())?;
-    
-    let mut inv_mask = core::Mat::default();
-    core::subtract(&core::Scalar::all(1.0), &mask_f32, &mut inv_mask, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
-    
-    let mut dst_masked = core::Mat::default();
-    core::multiply(&dst_roi_f32, &inv_mask, &mut dst_masked, 1.0, -1).map_err(|e| e.to_string())?;
-    
-    let mut blended_f32 = core::Mat::default();
-    core::add(&src_masked, &dst_masked, &mut blended_f32, &core::Mat::default(), -1).map_err(|e| e.to_string())?;
-    
-    let mut blended = core::Mat::default();
-    blended_f32.convert_to(&mut blended, core::CV_8U, 1.0, 0.0).map_err(|e| e.to_string())?;
-    
-    // 結果をdstにコピー
-    blended.copy_to(&mut core::Mat::roi_mut(dst, roi_rect).map_err(|e| e.to_string())?).map_err(|e| e.to_string())?;
+impl Default for ChipOptions {
+    fn default() -> Self {
+        ChipOptions {
+            size: 112,
+            mask_alpha: false,
+            json_manifest: true,
+            csv_manifest: true,
+            detect_options: DetectOptions::default(),
+            extract: ExtractOptions::default(),
+        }
+    }
+}
 
-    Ok(())
+// マニフェストの1行（1顔分）
+#[derive(serde::Serialize)]
+struct ChipRecord {
+    chip_file: String,              // 出力フォルダからの相対パス
+    source_file: String,
+    face_index: usize,              // 元画像内での順位（スコア順）
+    rect: FaceRect,                 // 元画像座標
+    score: f64,
+    landmarks: Vec<[f32; 2]>,       // 68点（元画像座標）
+    chip_landmarks: Vec<[f32; 2]>,  // 5点（チップ座標）
+    pose: HeadPose,
+    quality: FaceQuality,
 }
 
-fn blend_with_mask(src: &core::Mat, dst: &mut core::Mat, mask: &core::Mat, x: i32, y: i32) -> Result<(), String> {
-    let height = src.rows();
-    let width = src.cols();
+#[derive(serde::Serialize)]
+struct ChipExportResult {
+    chips: usize,                  // 書き出した顔の数
+    files: usize,                  // 処理した画像の数
+    errors: Vec<String>,           // 失敗した画像・顔と理由
+    manifest_json: Option<String>,
+    manifest_csv: Option<String>,
+}
+
+// 画像（またはフォルダ内の全画像）の顔を5点ランドマークで正規化した切り抜きとマニフェストを書き出す
+#[tauri::command]
+fn export_face_chips(input_path: String, output_dir: String, options: Option<ChipOptions>) -> Result<ChipExportResult, String> {
+    opencv::core::set_use_optimized(true).ok();
 
-    for row in 0..height {
-        for col in 0..width {
-            let dst_y = y + row;
-            let dst_x = x + col;
+    let options = options.unwrap_or_default();
+    let input = std::path::Path::new(&input_path);
+    let output = std::path::Path::new(&output_dir);
+    std::fs::create_dir_all(output).map_err(|e| format!("出力フォルダを作成できません: {}", e))?;
 
-            if dst_y >= 0 && dst_y < dst.rows() && dst_x >= 0 && dst_x < dst.cols() {
-                let alpha = *mask.at_2d::<u8>(row, col).map_err(|e| e.to_string())? as f32 / 255.0;
-                let src_pixel = src.at_2d::<core::Vec3b>(row, col).map_err(|e| e.to_string())?;
-                let dst_pixel = dst.at_2d_mut::<core::Vec3b>(dst_y, dst_x).map_err(|e| e.to_string())?;
+    let files = if input.is_dir() { list_image_files(input)? } else { vec![input.to_path_buf()] };
 
-                for c in 0..3 {
-                    dst_pixel[c] = (src_pixel[c] as f32 * alpha + dst_pixel[c] as f32 * (1.0 - alpha)) as u8;
-                }
+    // ランドマークのモデルはスレッドごとに1回だけ読み込む
+    let per_file: Vec<Result<(Vec<ChipRecord>, Vec<String>), String>> = files.par_iter()
+        .map_init(LazyFacemark::new, |facemark, file| export_file_chips(facemark, file, output, &options))
+        .collect();
+
+    let mut records = Vec::new();
+    let mut errors = Vec::new();
+    for (file, result) in files.iter().zip(per_file) {
+        match result {
+            Ok((chips, skipped)) => {
+                records.extend(chips);
+                errors.extend(skipped.into_iter().map(|e| format!("{}: {}", file.display(), e)));
             }
+            Err(e) => errors.push(format!("{}: {}", file.display(), e)),
         }
     }
 
-    Ok(())
+    let mut manifest_json = None;
+    if options.json_manifest {
+        let path = output.join("manifest.json");
+        let json = serde_json::to_string_pretty(&records).map_err(|e| e.to_string())?;
+        std::fs::write(&path, json).map_err(|e| format!("マニフェストを保存できません: {}", e))?;
+        manifest_json = Some(path.to_string_lossy().to_string());
+    }
+    let mut manifest_csv = None;
+    if options.csv_manifest {
+        let path = output.join("manifest.csv");
+        std::fs::write(&path, chip_manifest_csv(&records)).map_err(|e| format!("マニフェストを保存できません: {}", e))?;
+        manifest_csv = Some(path.to_string_lossy().to_string());
+    }
+
+    Ok(ChipExportResult {
+        chips: records.len(),
+        files: files.len(),
+        errors,
+        manifest_json,
+        manifest_csv,
+    })
+}
+
+// 戻り値は（書き出したチップ, スキップした顔とその理由）
+fn export_file_chips(facemark: &mut LazyFacemark, file: &std::path::Path, output: &std::path::Path, options: &ChipOptions) -> Result<(Vec<ChipRecord>, Vec<String>), String> {
+    let img = imgcodecs::imread(&file.to_string_lossy(), imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "画像の読み込みに失敗")?;
+    if img.empty() {
+        return Err("画像の読み込みに失敗".to_string());
+    }
+    let img_size = img.size().map_err(|e| e.to_string())?;
+    let faces = detect_faces(&img, &options.detect_options)?.faces;
+
+    // a.jpgとa.pngが同じフォルダにあっても衝突しないよう拡張子ごと名前に使う
+    let file_name = file.file_name().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let chip_size = core::Size::new(options.size, options.size);
+    let template: Vec<core::Point2f> = CHIP_TEMPLATE_112.iter()
+        .map(|p| core::Point2f::new(p[0] * options.size as f32 / 112.0, p[1] * options.size as f32 / 112.0))
+        .collect();
+
+    let mut records = Vec::new();
+    let mut skipped = Vec::new();
+    for (index, detected) in faces.iter().enumerate() {
+        // ランドマークが取れない顔はチップにしない（位置合わせできないため）
+        let landmarks = match facemark.landmarks(&img, detected.rect) {
+            Ok(landmarks) => landmarks,
+            Err(e) => {
+                skipped.push(format!("顔{}: {}", index, e));
+                continue;
+            }
+        };
+        let pose = match estimate_head_pose(&landmarks, img_size) {
+            Ok(pose) => pose,
+            Err(e) => {
+                skipped.push(format!("顔{}: {}", index, e));
+                continue;
+            }
+        };
+        let five = [
+            landmark_center(&landmarks, LANDMARK_RIGHT_EYE),
+            landmark_center(&landmarks, LANDMARK_LEFT_EYE),
+            landmarks[30],
+            landmarks[48],
+            landmarks[54],
+        ];
+
+        let from = core::Vector::<core::Point2f>::from_iter(five.iter().copied());
+        let to = core::Vector::<core::Point2f>::from_iter(template.iter().copied());
+        let mut inliers = core::Mat::default();
+        let transform = calib3d::estimate_affine_partial_2d(&from, &to, &mut inliers, calib3d::LMEDS, 3.0, 2000, 0.99, 10)
+            .map_err(|e| e.to_string())?;
+        if transform.empty() {
+            skipped.push(format!("顔{}: 顔の位置合わせに失敗", index));
+            continue;
+        }
+
+        let mut chip = core::Mat::default();
+        imgproc::warp_affine(&img, &mut chip, &transform, chip_size, imgproc::INTER_LINEAR, core::BORDER_REFLECT, core::Scalar::default())
+            .map_err(|e| e.to_string())?;
+
+        if options.mask_alpha {
+            let (canvas, _, mask) = extract_face_with_mask(&img, &detected.rect, &options.extract)?;
+            let full_mask = paste_mask(img_size, canvas, &mask)?;
+            let mut chip_mask = core::Mat::default();
+            imgproc::warp_affine(&full_mask, &mut chip_mask, &transform, chip_size, imgproc::INTER_LINEAR, core::BORDER_CONSTANT, core::Scalar::all(0.0))
+                .map_err(|e| e.to_string())?;
+            chip = merge_alpha(&chip, &chip_mask)?;
+        }
+
+        let chip_file = format!("{}_{}.png", file_name, index);
+        write_image(&output.join(&chip_file), &chip)?;
+
+        records.push(ChipRecord {
+            chip_file,
+            source_file: file.to_string_lossy().to_string(),
+            face_index: index,
+            rect: FaceRect::from(detected.rect),
+            score: detected.score,
+            landmarks: landmarks.iter().map(|p| [p.x, p.y]).collect(),
+            chip_landmarks: transform_points(&five, &transform)?.iter().map(|p| [p.x, p.y]).collect(),
+            pose,
+            quality: assess_face_quality(&img, detected.rect)?,
+        });
+    }
+
+    Ok((records, skipped))
+}
+
+// マニフェストのCSV（ランドマークは"x y x y ..."の1列にまとめる）
+fn chip_manifest_csv(records: &[ChipRecord]) -> String {
+    let field = |value: &str| {
+        if value.contains([',', '"', '\n']) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    };
+    let points = |points: &[[f32; 2]]| points.iter().map(|p| format!("{:.2} {:.2}", p[0], p[1])).collect::<Vec<_>>().join(" ");
+
+    let mut csv = String::from("chip_file,source_file,face_index,x,y,width,height,score,yaw,pitch,roll,sharpness,highlight_clip_ratio,shadow_clip_ratio,skin_coverage,chip_landmarks,landmarks\n");
+    for r in records {
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{:.4},{:.2},{:.2},{:.2},{:.2},{:.4},{:.4},{:.4},{},{}\n",
+            field(&r.chip_file), field(&r.source_file), r.face_index,
+            r.rect.x, r.rect.y, r.rect.width, r.rect.height, r.score,
+            r.pose.yaw, r.pose.pitch, r.pose.roll,
+            r.quality.sharpness, r.quality.highlight_clip_ratio, r.quality.shadow_clip_ratio, r.quality.skin_coverage,
+            points(&r.chip_landmarks), points(&r.landmarks),
+        ));
+    }
+    csv
+}
+
+// ---- ソース顔の自動選択 ----
+
+// ソース候補の順位付けの設定（フロントエンドから省略されたフィールドはデフォルト値）
+#[derive(serde::Deserialize, Clone)]
+#[serde(default)]
+struct RankOptions {
+    top_k: usize,             // 返す件数
+    swap_with_best: bool,     // 1位の候補でそのままface swapする
+    pose_weight: f64,         // 顔の向きの近さの重み
+    lighting_weight: f64,     // 肌の明るさ・色味の近さの重み
+    resolution_weight: f64,   // 解像度（拡大しすぎないか）の重み
+    sharpness_weight: f64,    // シャープさの重み
+    detect_options: DetectOptions,
+    swap_options: SwapOptions,
+}
+
+impl Default for RankOptions {
+    fn default() -> Self {
+        RankOptions {
+            top_k: 5,
+            swap_with_best: false,
+            pose_weight: 0.4,
+            lighting_weight: 0.3,
+            resolution_weight: 0.15,
+            sharpness_weight: 0.15,
+            detect_options: DetectOptions::default(),
+            swap_options: SwapOptions::default(),
+        }
+    }
+}
+
+// 候補1件の評価（各スコアは0.0-1.0で大きいほど良い）
+#[derive(serde::Serialize, Clone)]
+struct SourceRanking {
+    path: String,
+    library_id: Option<String>,
+    score: f64,             // 重み付きの総合スコア
+    pose_score: f64,
+    lighting_score: f64,
+    resolution_score: f64,
+    sharpness_score: f64,
+    yaw_difference: Option<f64>,  // ターゲットとのyawの差（度）
+}
+
+#[derive(serde::Serialize)]
+struct RankResult {
+    rankings: Vec<SourceRanking>,
+    skipped: Vec<String>,           // 顔が検出できなかった候補
+    swap: Option<FaceSwapResult>,   // swap_with_bestの時の合成結果
+}
+
+// ターゲットに貼るのに向いたソース顔を、フォルダとライブラリの候補から選ぶ
+#[tauri::command]
+fn rank_sources(
+    app: tauri::AppHandle,
+    target_path: String,
+    folder: Option<String>,
+    use_library: Option<bool>,
+    library_tags: Option<Vec<String>>,
+    options: Option<RankOptions>,
+) -> Result<RankResult, String> {
+    opencv::core::set_use_optimized(true).ok();
+
+    let options = options.unwrap_or_default();
+
+    // 1. 候補を集める（パス, ライブラリID）
+    let mut candidates: Vec<(String, Option<String>)> = Vec::new();
+    if let Some(folder) = &folder {
+        for file in list_image_files(std::path::Path::new(folder))? {
+            candidates.push((file.to_string_lossy().to_string(), None));
+        }
+    }
+    if use_library.unwrap_or(false) {
+        let dir = library_dir(&app)?;
+        for entry in library_list(app.clone(), None, library_tags)? {
+            candidates.push((library_file(&dir, &entry.image_file)?.to_string_lossy().to_string(), Some(entry.id)));
+        }
+    }
+    if candidates.is_empty() {
+        return Err("ソース候補がありません".to_string());
+    }
+
+    // 2. ターゲット顔の特徴
+    let target_img = imgcodecs::imread(&target_path, imgcodecs::IMREAD_COLOR)
+        .map_err(|_| "ターゲット画像の読み込みに失敗")?;
+    let target_face = detect_faces(&target_img, &options.detect_options)?.faces
+        .first().ok_or("ターゲット画像に顔が検出されませんでした")?.rect;
+    let mut facemark = LazyFacemark::new();
+    let target_profile = face_profile(&mut facemark, &target_img, target_face, None)?;
+
+    // 3. 候補ごとに採点
+    let mut rankings = Vec::new();
+    let mut skipped = Vec::new();
+    for (path, library_id) in candidates {
+        let (img, alpha) = match read_with_alpha(&path) {
+            Ok(loaded) => loaded,
+            Err(_) => {
+                skipped.push(path);
+                continue;
+            }
+        };
+        let face = match (detect_faces(&img, &options.detect_options)?.faces.first(), &alpha) {
+            (Some(face), _) => face.rect,
+            (None, Some(_)) => face_rect_in_canvas(img.size().map_err(|e| e.to_string())?, &options.swap_options.extract),
+            (None, None) => {
+                skipped.push(path);
+                continue;
+            }
+        };
+        let profile = face_profile(&mut facemark, &img, face, Some(target_face.size()))?;
+
+        // 向き: yawとpitchの差が45度で0点（自動反転するなら反転後の差で比べる）
+        let yaw_difference = match (&profile.pose, &target_profile.pose) {
+            (Some(sp), Some(tp)) => {
+                let direct = (sp.yaw - tp.yaw).abs();
+                let mirrored = (-sp.yaw - tp.yaw).abs();
+                Some(if options.swap_options.auto_mirror { direct.min(mirrored) } else { direct })
+            }
+            _ => None,
+        };
+        let pose_score = match (yaw_difference, &profile.pose, &target_profile.pose) {
+            (Some(dy), Some(sp), Some(tp)) => {
+                let dp = sp.pitch - tp.pitch;
+                1.0 - ((dy * dy + dp * dp).sqrt() / 45.0).min(1.0)
+            }
+            _ => 0.5, // 向きが分からない候補は中間の扱い
+        };
+
+        // 照明: 肌のLab平均の距離（色補正強度の計算と同じ双曲線カーブ）
+        let lab_distance = profile.skin_lab.iter().zip(target_profile.skin_lab.iter())
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f64>()
+            .sqrt();
+        let lighting_score = 40.0 / (lab_distance + 40.0);
+
+        // 解像度: ターゲットより小さい分だけ減点
+        let resolution_score = (face.width as f64 / target_face.width as f64).min(1.0);
+
+        // シャープさ: ターゲットサイズに揃えた時にターゲット以上なら満点
+        let sharpness_score = if target_profile.sharpness > 0.0 {
+            (profile.sharpness / target_profile.sharpness).min(1.0)
+        } else {
+            1.0
+        };
+
+        let score = options.pose_weight * pose_score
+            + options.lighting_weight * lighting_score
+            + options.resolution_weight * resolution_score
+            + options.sharpness_weight * sharpness_score;
+
+        rankings.push(SourceRanking {
+            path,
+            library_id,
+            score,
+            pose_score,
+            lighting_score,
+            resolution_score,
+            sharpness_score,
+            yaw_difference,
+        });
+    }
+
+    rankings.sort_by(|a, b| b.score.total_cmp(&a.score));
+    rankings.truncate(options.top_k.max(1));
+
+    // 4. 必要なら1位でそのまま合成
+    let swap = match (options.swap_with_best, rankings.first()) {
+        (true, Some(best)) => Some(face_swap(
+            app.clone(),
+            best.path.clone(),
+            target_path.clone(),
+            None,
+            Some(options.detect_options.clone()),
+            Some(options.swap_options.clone()),
+        )?),
+        _ => None,
+    };
+
+    Ok(RankResult { rankings, skipped, swap })
+}
+
+// 順位付けに使う顔の特徴
+struct FaceProfile {
+    pose: Option<HeadPose>,
+    skin_lab: [f64; 3],  // 肌色画素のLab平均
+    sharpness: f64,      // compare_sizeに揃えた時のラプラシアン分散
+}
+
+fn face_profile(facemark: &mut LazyFacemark, img: &core::Mat, face: core::Rect, compare_size: Option<core::Size>) -> Result<FaceProfile, String> {
+    let pose = match facemark.landmarks(img, face) {
+        Ok(landmarks) => Some(estimate_head_pose(&landmarks, img.size().map_err(|e| e.to_string())?)?),
+        Err(_) => None,
+    };
+
+    let roi = core::Mat::roi(img, face).map_err(|e| e.to_string())?;
+    let mut face_img = core::Mat::default();
+    roi.copy_to(&mut face_img).map_err(|e| e.to_string())?;
+
+    let skin = create_skin_mask(&face_img)?;
+    let mut lab = core::Mat::default();
+    imgproc::cvt_color(&face_img, &mut lab, imgproc::COLOR_BGR2Lab, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+    let lab_mean = core::mean(&lab, &skin).map_err(|e| e.to_string())?;
+
+    let mut resized = face_img.clone();
+    if let Some(size) = compare_size {
+        imgproc::resize(&face_img, &mut resized, size, 0.0, 0.0, imgproc::INTER_LANCZOS4).map_err(|e| e.to_string())?;
+    }
+    let mut gray = core::Mat::default();
+    imgproc::cvt_color(&resized, &mut gray, imgproc::COLOR_BGR2GRAY, 0, core::AlgorithmHint::ALGO_HINT_DEFAULT).map_err(|e| e.to_string())?;
+
+    Ok(FaceProfile {
+        pose,
+        skin_lab: [lab_mean[0], lab_mean[1], lab_mean[2]],
+        sharpness: laplacian_variance(&gray)?,
+    })
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -640,7 +4494,254 @@ pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_dialog::init())
-        .invoke_handler(tauri::generate_handler![greet, process_face, face_swap])
+        .setup(|app| {
+            if let Ok(dir) = app.path().resource_dir() {
+                RESOURCE_DIR.set(dir).ok();
+            }
+            Ok(())
+        })
+        .invoke_handler(tauri::generate_handler![
+            greet, process_face, face_swap, assess_faces, remove_face, anonymize_faces, partial_swap, face_morph, composite_faces,
+            library_add, library_list, library_set_tags, library_remove, library_export, library_import,
+            rank_sources, refine_mask, close_mask_session, composite_extracted,
+            list_id_photo_specs, create_id_photo, export_face_chips,
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detected(x: i32, y: i32, width: i32, height: i32, score: f64) -> DetectedFace {
+        DetectedFace { rect: core::Rect::new(x, y, width, height), score, verified: None }
+    }
+
+    #[test]
+    fn rect_overlap_disjoint_and_identical() {
+        let a = core::Rect::new(0, 0, 10, 10);
+        assert_eq!(rect_overlap(&a, &core::Rect::new(10, 0, 10, 10)), (0.0, 0.0));
+        assert_eq!(rect_overlap(&a, &a), (1.0, 1.0));
+    }
+
+    #[test]
+    fn rect_overlap_partial_and_nested() {
+        // 半分ずつ重なる: 交差50、和150
+        let (iou, containment) = rect_overlap(&core::Rect::new(0, 0, 10, 10), &core::Rect::new(5, 0, 10, 10));
+        assert!((iou - 50.0 / 150.0).abs() < 1e-9);
+        assert!((containment - 0.5).abs() < 1e-9);
+
+        // 入れ子: IoUは小さくても小さい方は丸ごと含まれる
+        let (iou, containment) = rect_overlap(&core::Rect::new(0, 0, 100, 100), &core::Rect::new(10, 10, 10, 10));
+        assert!((iou - 0.01).abs() < 1e-9);
+        assert_eq!(containment, 1.0);
+    }
+
+    #[test]
+    fn non_max_suppression_keeps_best_of_overlapping() {
+        let candidates = vec![
+            detected(2, 2, 100, 100, 0.5),
+            detected(0, 0, 100, 100, 0.9),
+            detected(300, 300, 80, 80, 0.7),
+        ];
+        let kept = non_max_suppression(candidates, &DetectOptions::default());
+        let scores: Vec<f64> = kept.iter().map(|f| f.score).collect();
+        assert_eq!(scores, vec![0.9, 0.7]);
+    }
+
+    #[test]
+    fn non_max_suppression_drops_nested_boxes() {
+        let candidates = vec![
+            detected(0, 0, 200, 200, 0.8),
+            detected(50, 50, 60, 60, 0.9),
+        ];
+        let kept = non_max_suppression(candidates, &DetectOptions::default());
+        assert_eq!(kept.len(), 1);
+        assert_eq!(kept[0].rect, core::Rect::new(50, 50, 60, 60));
+
+        // 包含のしきい値を上げれば両方残る
+        let options = DetectOptions { containment_threshold: 1.1, ..DetectOptions::default() };
+        let kept = non_max_suppression(vec![detected(0, 0, 200, 200, 0.8), detected(50, 50, 60, 60, 0.9)], &options);
+        assert_eq!(kept.len(), 2);
+    }
+
+    #[test]
+    fn face_rect_in_canvas_inverts_extract_geometry() {
+        let face = core::Rect::new(800, 700, 200, 240);
+        let img_size = core::Size::new(3000, 3000);
+        let presets = [None, Some(ExtractPreset::Face), Some(ExtractPreset::FaceHair), Some(ExtractPreset::FullHead)];
+        for preset in presets {
+            let options = ExtractOptions { preset, ..ExtractOptions::default() };
+            let canvas = extract_geometry(&face, img_size, &options).canvas;
+            let restored = face_rect_in_canvas(canvas.size(), &options);
+
+            // 余白の切り捨てで数pxずれるのは許容する
+            assert!((restored.x - (face.x - canvas.x)).abs() <= 2, "x: {:?} / {:?}", restored, canvas);
+            assert!((restored.y - (face.y - canvas.y)).abs() <= 2, "y: {:?} / {:?}", restored, canvas);
+            assert!((restored.width - face.width).abs() <= 2, "width: {:?}", restored);
+            assert!((restored.height - face.height).abs() <= 2, "height: {:?}", restored);
+        }
+    }
+
+    #[test]
+    fn id_photo_grid_centers_the_grid() {
+        let slots = id_photo_grid(core::Size::new(100, 100), core::Size::new(30, 40), 5, None);
+        let origins: Vec<(i32, i32)> = slots.iter().map(|r| (r.x, r.y)).collect();
+        assert_eq!(origins, vec![(17, 7), (52, 7), (17, 52), (52, 52)]);
+        assert!(slots.iter().all(|r| r.width == 30 && r.height == 40));
+    }
+
+    #[test]
+    fn id_photo_grid_limits_copies_to_capacity() {
+        let sheet = core::Size::new(100, 100);
+        let photo = core::Size::new(30, 40);
+        assert_eq!(id_photo_grid(sheet, photo, 5, Some(3)).len(), 3);
+        assert_eq!(id_photo_grid(sheet, photo, 5, Some(10)).len(), 4);
+        assert!(id_photo_grid(sheet, core::Size::new(120, 40), 5, None).is_empty());
+    }
+
+    #[test]
+    fn id_photo_grid_fits_l_size_sheet() {
+        // L判（89×127mm）に35×45mmを300dpiで並べると2列×2行、シートからはみ出さない
+        let px_per_mm = 300.0 / 25.4;
+        let mm = |w: f64, h: f64| core::Size::new((w * px_per_mm).round() as i32, (h * px_per_mm).round() as i32);
+        let sheet = mm(89.0, 127.0);
+        let slots = id_photo_grid(sheet, mm(35.0, 45.0), (2.0 * px_per_mm).round() as i32, None);
+        assert_eq!(slots.len(), 4);
+        assert!(slots.iter().all(|r| r.x >= 0 && r.y >= 0 && r.x + r.width <= sheet.width && r.y + r.height <= sheet.height));
+    }
+
+    #[test]
+    fn chip_manifest_csv_quotes_special_fields() {
+        let record = ChipRecord {
+            chip_file: "a,b.jpg_0.png".to_string(),
+            source_file: "say \"hi\".jpg".to_string(),
+            face_index: 0,
+            rect: FaceRect { x: 1, y: 2, width: 3, height: 4 },
+            score: 0.5,
+            landmarks: vec![[1.0, 2.0]],
+            chip_landmarks: vec![[3.0, 4.0]],
+            pose: HeadPose { yaw: 0.0, pitch: 0.0, roll: 0.0 },
+            quality: FaceQuality { width: 3, height: 4, sharpness: 0.0, highlight_clip_ratio: 0.0, shadow_clip_ratio: 0.0, skin_coverage: 0.0 },
+        };
+        let csv = chip_manifest_csv(&[record]);
+        let mut lines = csv.lines();
+        let header_columns = lines.next().unwrap().split(',').count();
+        let row = lines.next().unwrap();
+
+        assert!(row.starts_with("\"a,b.jpg_0.png\",\"say \"\"hi\"\".jpg\",0,1,2,3,4,"));
+        assert!(row.ends_with(",3.00 4.00,1.00 2.00"));
+        // 引用符で囲んだカンマを除けば列数はヘッダと同じ
+        assert_eq!(row.split(',').count() - 1, header_columns);
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn library_id_accepts_generated_ids_only() {
+        assert!(is_valid_library_id(&new_unique_id()));
+        assert!(is_valid_library_id("face_01"));
+        for id in ["", "../evil", "a/b", "a\\b", "..", "a.b", "名前", &"x".repeat(65)] {
+            assert!(!is_valid_library_id(id), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn library_file_rejects_path_traversal() {
+        let dir = std::path::Path::new("library");
+        assert_eq!(library_file(dir, "abc.png"), Ok(dir.join("abc.png")));
+        for name in ["", ".", "..", "../abc.png", "sub/abc.png", "sub\\abc.png", "/etc/passwd", "./abc.png"] {
+            assert!(library_file(dir, name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn alpha_matting_softens_only_the_unknown_band() {
+        // 左が白・右が黒でx=22..38に明るさの傾斜がある画像と、x=30で切り替わる2値マスク
+        let (rows, cols) = (40, 60);
+        let mut img = core::Mat::new_rows_cols_with_default(rows, cols, core::CV_8UC3, core::Scalar::all(0.0)).unwrap();
+        let mut mask = core::Mat::new_rows_cols_with_default(rows, cols, core::CV_8UC1, core::Scalar::all(0.0)).unwrap();
+        for y in 0..rows {
+            for x in 0..cols {
+                let v = (255.0 * (38 - x) as f64 / 16.0).clamp(0.0, 255.0) as u8;
+                *img.at_2d_mut::<core::Vec3b>(y, x).unwrap() = core::Vec3b::from([v, v, v]);
+                *mask.at_2d_mut::<u8>(y, x).unwrap() = if x < 30 { 255 } else { 0 };
+            }
+        }
+
+        let options = MattingOptions { radius: 4, decontaminate: false, ..MattingOptions::default() };
+        let (_, alpha) = alpha_matting(&img, &mask, &options).unwrap();
+        let row: Vec<u8> = (0..cols).map(|x| *alpha.at_2d::<u8>(rows / 2, x).unwrap()).collect();
+
+        // 4px削った内側は確実な前景、8px広げた外側は確実な背景
+        assert!(row[..26].iter().all(|&a| a == 255), "{:?}", row);
+        assert!(row[38..].iter().all(|&a| a == 0), "{:?}", row);
+        // その間の未知の帯は輝度の傾斜に沿った半透明になる
+        for x in [28, 30, 32] {
+            assert!(row[x] > 0 && row[x] < 255, "x={}: {:?}", x, row);
+        }
+        assert!(row[27] > row[30] && row[30] > row[33], "{:?}", row);
+    }
+
+    // 暗い背景の中央に明るい正方形（弱いノイズ入り）
+    fn grab_cut_test_image() -> core::Mat {
+        let mut img = core::Mat::new_rows_cols_with_default(80, 80, core::CV_8UC3, core::Scalar::new(40.0, 60.0, 50.0, 0.0)).unwrap();
+        imgproc::rectangle(&mut img, core::Rect::new(25, 25, 30, 30), core::Scalar::new(200.0, 180.0, 220.0, 0.0), -1, imgproc::LINE_8, 0).unwrap();
+        let mut noise = core::Mat::new_rows_cols_with_default(80, 80, core::CV_8UC3, core::Scalar::all(0.0)).unwrap();
+        core::randu(&mut noise, &core::Scalar::all(0.0), &core::Scalar::all(8.0)).unwrap();
+        let mut noisy = core::Mat::default();
+        core::add(&img, &noise, &mut noisy, &core::Mat::default(), -1).unwrap();
+        noisy
+    }
+
+    #[test]
+    fn grab_cut_stops_at_convergence_threshold() {
+        let img = grab_cut_test_image();
+        let rect = core::Rect::new(15, 15, 50, 50);
+
+        let options = ExtractOptions { max_iterations: 25, iteration_step: 1, convergence_threshold: 0.001, ..ExtractOptions::default() };
+        let state = run_grab_cut(&img, rect, &options).unwrap();
+        assert!(state.iterations < 25, "iterations: {}", state.iterations);
+    }
+
+    #[test]
+    fn grab_cut_caps_iterations_without_convergence() {
+        // しきい値0では収束とみなさないので、端数のステップも含めて上限ちょうどまで反復する
+        let options = ExtractOptions { max_iterations: 12, iteration_step: 5, convergence_threshold: 0.0, ..ExtractOptions::default() };
+        let state = run_grab_cut(&grab_cut_test_image(), core::Rect::new(15, 15, 50, 50), &options).unwrap();
+        assert_eq!(state.iterations, 12);
+    }
+
+    #[test]
+    fn merge_alpha_reads_non_continuous_views() {
+        let (rows, cols) = (10, 12);
+        let mut img = core::Mat::new_rows_cols_with_default(rows, cols, core::CV_8UC3, core::Scalar::all(0.0)).unwrap();
+        let mut mask = core::Mat::new_rows_cols_with_default(rows, cols, core::CV_8UC1, core::Scalar::all(0.0)).unwrap();
+        for y in 0..rows {
+            for x in 0..cols {
+                *img.at_2d_mut::<core::Vec3b>(y, x).unwrap() = core::Vec3b::from([x as u8, y as u8, (x + y) as u8]);
+                *mask.at_2d_mut::<u8>(y, x).unwrap() = (x * 10 + y) as u8;
+            }
+        }
+
+        // 大きい画像の一部を指す、行の間隔（step）が幅より長いMat
+        let rect = core::Rect::new(2, 3, 5, 4);
+        let view = |m: &mut core::Mat, elem: usize| unsafe {
+            let data = m.data_mut().add((rect.y * cols + rect.x) as usize * elem);
+            core::Mat::new_rows_cols_with_data_unsafe(rect.height, rect.width, m.typ(), data.cast(), cols as usize * elem).unwrap()
+        };
+        let img_view = view(&mut img, 3);
+        let mask_view = view(&mut mask, 1);
+        assert!(!img_view.is_continuous() && !mask_view.is_continuous());
+
+        let bgra = merge_alpha(&img_view, &mask_view).unwrap();
+        assert_eq!((bgra.rows(), bgra.cols()), (rect.height, rect.width));
+        for y in 0..rect.height {
+            for x in 0..rect.width {
+                let (sx, sy) = (x + rect.x, y + rect.y);
+                let pixel = bgra.at_2d::<core::Vec4b>(y, x).unwrap();
+                assert_eq!(pixel.0, [sx as u8, sy as u8, (sx + sy) as u8, (sx * 10 + sy) as u8], "({}, {})", x, y);
+            }
+        }
+    }
+}
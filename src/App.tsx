@@ -7,11 +7,73 @@ import "./App.css";
 interface FaceResult {
   base64: string;       // 切り抜き後の画像 (PNG)
   debug_base64: string; // 解析用画像 (JPG: 赤枠・青枠付き)
+  score: number;        // 検出の信頼度
+  verified: boolean | null; // 目の検証結果 (検証OFFならnull)
+  landmarks: [number, number][] | null; // 68点ランドマーク
+  pose: HeadPose | null;    // 顔の向き
+  quality: FaceQuality;     // 画質の指標
+  session_id: string;       // refine_maskでマスクを修正する時のID
+  face_rect: FaceRect;      // 顔矩形 (元画像座標)
+  canvas_rect: FaceRect;    // 切り抜き範囲 (元画像座標)
+  mask_base64: string | null;      // アルファマスク (PNG)
+  label_map_base64: string | null; // GrabCutのラベル (PNG)
+  contour: [number, number][] | null; // マスクの外形 (元画像座標)
+  timings: MaskTimings;     // マスク生成の処理時間
+  warnings: string[];       // 省略した処理とその理由
+}
+
+interface MaskTimings {
+  grab_cut_ms: number;  // GrabCut (作業解像度)
+  upsample_ms: number;  // 元の解像度へのアップサンプル
+  work_width: number;
+  work_height: number;
+  iterations: number;   // GrabCutの反復回数
+}
+
+interface FaceRect {
+  x: number;
+  y: number;
+  width: number;
+  height: number;
+}
+
+interface FaceQuality {
+  width: number;
+  height: number;
+  sharpness: number;            // ラプラシアンの分散
+  highlight_clip_ratio: number; // 白飛びの割合
+  shadow_clip_ratio: number;    // 黒つぶれの割合
+  skin_coverage: number;        // 肌色の割合
+}
+
+interface HeadPose {
+  yaw: number;   // 左右 (度)
+  pitch: number; // 上下 (度)
+  roll: number;  // 傾き (度)
+}
+
+interface TextureAdjustment {
+  source_sharpness: number;
+  target_sharpness: number;
+  source_noise: number;
+  target_noise: number;
+  blur_sigma: number;     // 適用したぼかし
+  sharpen_amount: number; // 適用したシャープ化
+  noise_sigma: number;    // 追加したノイズ
 }
 
 interface FaceSwapResult {
   base64: string;  // 合成結果画像
   color_correction_strength: number;  // 使用された色補正強度
+  source_quality: FaceQuality;
+  target_quality: FaceQuality;
+  texture_adjustment: TextureAdjustment | null; // シャープさ・ノイズ合わせ
+  inpainted_pixels: number; // 修復した画素数
+  source_pose: HeadPose | null;
+  target_pose: HeadPose | null;
+  mirrored: boolean;   // ソースを左右反転したか
+  used_source_alpha: boolean; // ソースのアルファを合成マスクに使ったか
+  warnings: string[];  // 不自然になりそうな点
 }
 
 function App() {
@@ -20,6 +82,7 @@ function App() {
   // Face Extraction用
   const [results, setResults] = useState<FaceResult[]>([]);
   const [loading, setLoading] = useState<boolean>(false);
+  const [extractPreset, setExtractPreset] = useState<'face' | 'face_hair' | 'full_head'>('face_hair'); // 切り抜き範囲
 
   // Face Swap用
   const [sourcePath, setSourcePath] = useState<string>("");
@@ -27,9 +90,11 @@ function App() {
   const [sourcePreview, setSourcePreview] = useState<string>("");
   const [targetPreview, setTargetPreview] = useState<string>("");
   const [swapResult, setSwapResult] = useState<string>("");
+  const [swapWarnings, setSwapWarnings] = useState<string[]>([]);
   const [swapping, setSwapping] = useState<boolean>(false);
   const [colorCorrection, setColorCorrection] = useState<number | null>(null); // 色補正強度 (0-1)、nullの場合は自動
   const [manualMode, setManualMode] = useState<boolean>(false); // 手動モードのオン/オフ
+  const [swapMode, setSwapMode] = useState<'face' | 'head'>('face'); // 顔だけ / 髪ごと
 
   const selectAndProcess = async () => {
     const file = await open({
@@ -41,7 +106,10 @@ function App() {
       setLoading(true);
       setResults([]);
       try {
-        const data = await invoke<FaceResult[]>("process_face", { path: file });
+        const data = await invoke<FaceResult[]>("process_face", {
+          path: file,
+          extractOptions: { preset: extractPreset }
+        });
         setResults(data);
       } catch (e) {
         console.error(e);
@@ -85,14 +153,17 @@ function App() {
 
     setSwapping(true);
     setSwapResult("");
+    setSwapWarnings([]);
     try {
       const result = await invoke<FaceSwapResult>("face_swap", { 
         sourcePath, 
         targetPath,
         // 手動モードの時だけスライダーの値を使用、それ以外は自動計算
-        colorCorrection: manualMode && colorCorrection !== null ? colorCorrection : undefined
+        colorCorrection: manualMode && colorCorrection !== null ? colorCorrection : undefined,
+        swapOptions: { mode: swapMode }
       });
       setSwapResult(result.base64);
+      setSwapWarnings(result.warnings);
       // 自動計算された色補正強度をスライダーに反映
       setColorCorrection(result.color_correction_strength);
     } catch (e) {
@@ -245,6 +316,26 @@ function App() {
             </div>
           </div>
 
+          {/* 入れ替え範囲 */}
+          <div className="flex justify-center gap-2">
+            <button
+              onClick={() => setSwapMode('face')}
+              className={`px-4 py-2 rounded-full text-sm font-bold transition-all ${
+                swapMode === 'face' ? 'bg-cyan-500/30 border border-cyan-500/60 text-cyan-300' : 'bg-slate-800/70 border border-slate-700 text-slate-400'
+              }`}
+            >
+              🙂 顔だけ
+            </button>
+            <button
+              onClick={() => setSwapMode('head')}
+              className={`px-4 py-2 rounded-full text-sm font-bold transition-all ${
+                swapMode === 'head' ? 'bg-purple-500/30 border border-purple-500/60 text-purple-300' : 'bg-slate-800/70 border border-slate-700 text-slate-400'
+              }`}
+            >
+              💇 髪ごと (頭全体)
+            </button>
+          </div>
+
           {/* Swap Button */}
           <div className="flex justify-center py-4">
             <button
@@ -287,6 +378,11 @@ function App() {
                   <span className="text-lg">💾</span> ダウンロード
                 </button>
               </div>
+              {swapWarnings.length > 0 && (
+                <div className="mb-4 px-4 py-3 bg-yellow-500/10 border border-yellow-500/40 rounded-xl text-sm text-yellow-300 space-y-1">
+                  {swapWarnings.map((w, i) => <div key={i}>⚠️ {w}</div>)}
+                </div>
+              )}
               <div className="bg-slate-900/60 backdrop-blur rounded-xl overflow-hidden border-2 border-slate-700/50 flex items-center justify-center p-4 hover:border-purple-500/50 transition-colors">
                 <img 
                   src={`data:image/png;base64,${swapResult}`} 
@@ -301,6 +397,25 @@ function App() {
       {/* Face Extract Mode */}
       {mode === 'extract' && (
         <div className="w-full max-w-6xl space-y-8">
+          {/* 切り抜き範囲 */}
+          <div className="flex justify-center gap-2">
+            {([
+              ['face', '🙂 顔だけ'],
+              ['face_hair', '💇 顔 + 髪'],
+              ['full_head', '👤 頭全体 + 首'],
+            ] as const).map(([preset, label]) => (
+              <button
+                key={preset}
+                onClick={() => setExtractPreset(preset)}
+                className={`px-4 py-2 rounded-full text-sm font-bold transition-all ${
+                  extractPreset === preset ? 'bg-purple-500/30 border border-purple-500/60 text-purple-300' : 'bg-slate-800/70 border border-slate-700 text-slate-400'
+                }`}
+              >
+                {label}
+              </button>
+            ))}
+          </div>
+
           <div className="flex justify-center">
             <button 
               onClick={selectAndProcess}
@@ -328,7 +443,16 @@ function App() {
             <div key={index} className="bg-slate-800/50 p-6 rounded-3xl border border-slate-700">
               <h2 className="text-xl font-bold mb-4 text-slate-300">
                 Face #{index + 1}
+                <span className="ml-3 text-sm font-mono text-slate-500">score: {res.score.toFixed(2)}</span>
+                <span className="ml-3 text-sm font-mono text-slate-500">
+                  {res.quality.width}×{res.quality.height}px / sharpness: {res.quality.sharpness.toFixed(0)} / skin: {Math.round(res.quality.skin_coverage * 100)}% / mask: {res.timings.grab_cut_ms.toFixed(0)}+{res.timings.upsample_ms.toFixed(0)}ms ({res.timings.iterations}回)
+                </span>
               </h2>
+              {res.warnings.length > 0 && (
+                <div className="mb-4 px-4 py-3 bg-yellow-500/10 border border-yellow-500/40 rounded-xl text-sm text-yellow-300 space-y-1">
+                  {res.warnings.map((w, i) => <div key={i}>⚠️ {w}</div>)}
+                </div>
+              )}
               
               <div className="grid grid-cols-1 md:grid-cols-2 gap-6">
                 <div className="flex flex-col gap-2">
@@ -351,12 +475,13 @@ function App() {
                     <div className="absolute bottom-2 right-2 bg-black/80 p-2 rounded text-xs text-white space-y-1">
                       <div className="flex items-center gap-1"><div className="w-3 h-3 border border-blue-500"></div> 顔検出</div>
                       <div className="flex items-center gap-1"><div className="w-3 h-3 border border-red-500"></div> 探索範囲</div>
+                      <div className="flex items-center gap-1"><div className="w-3 h-3 border border-yellow-400"></div> 検証NG</div>
                     </div>
                   </div>
                   <p className="text-xs text-slate-400 mt-1">
                     ※ 赤枠の外側は「強制的に削除」されます。<br/>
-                    ※ 髪が切れるなら赤枠が髪より小さいです。<br/>
-                    ※ 首が残るなら赤枠が下まで伸びすぎています。
+                    ※ 髪が切れるなら赤枠が髪より小さいです（「頭全体 + 首」を試してください）。<br/>
+                    ※ 首が残るなら赤枠が下まで伸びすぎています（「顔 + 髪」か「顔だけ」を試してください）。
                   </p>
                 </div>
               </div>